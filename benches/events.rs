@@ -0,0 +1,86 @@
+use std::thread;
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use dipstick::AtomicBucket;
+use tracing::{debug, dispatcher, info_span, subscriber, Dispatch};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+fn events(c: &mut Criterion) {
+    let bridge = DipstickLayer::new(AtomicBucket::new());
+    let subscriber = Registry::default().with(bridge);
+    subscriber::with_default(subscriber, || {
+        let mut group = c.benchmark_group("event");
+        group.bench_function("plain", |b| b.iter(|| debug!("Nothing to measure")));
+        group.bench_function("root", |b| b.iter(|| debug!(metrics.counter = "hits")));
+        group.bench_function("valued", |b| b.iter(|| debug!(metrics.counter.legs = 4)));
+        let _span = info_span!("Scoped", metrics.scope = "scope").entered();
+        group.bench_function("scoped", |b| b.iter(|| debug!(metrics.counter = "hits")));
+        // The scope needs to be found through several spans without metrics.
+        let _plain = [
+            info_span!("Plain").entered(),
+            info_span!("Plain").entered(),
+            info_span!("Plain").entered(),
+        ];
+        group.bench_function("nested", |b| b.iter(|| debug!(metrics.counter = "hits")));
+        let _labeled = info_span!("Labeled", metrics.label.tenant = "yaks").entered();
+        group.bench_function("labeled", |b| b.iter(|| debug!(metrics.counter = "hits")));
+        group.finish();
+    });
+}
+
+fn spans(c: &mut Criterion) {
+    let bridge = DipstickLayer::new(AtomicBucket::new());
+    let subscriber = Registry::default().with(bridge);
+    subscriber::with_default(subscriber, || {
+        let mut group = c.benchmark_group("span");
+        group.bench_function("plain", |b| b.iter(|| info_span!("Plain").entered()));
+        group.bench_function("scoped", |b| {
+            b.iter(|| {
+                info_span!(
+                    "Yak",
+                    metrics.scope = "yak",
+                    metrics.timer = "time",
+                    metrics.level = "active"
+                )
+                .entered()
+            })
+        });
+        group.finish();
+    });
+}
+
+/// Events from multiple threads at once, into the same scope.
+fn threads(c: &mut Criterion) {
+    const THREADS: u64 = 4;
+    let bridge = DipstickLayer::new(AtomicBucket::new());
+    let dispatch = Dispatch::new(Registry::default().with(bridge));
+    let span =
+        dispatcher::with_default(&dispatch, || info_span!("Scoped", metrics.scope = "scope"));
+    let mut group = c.benchmark_group("threads");
+    group.throughput(Throughput::Elements(THREADS));
+    group.bench_function("scoped", |b| {
+        b.iter_custom(|iters| {
+            let start = Instant::now();
+            thread::scope(|s| {
+                for _ in 0..THREADS {
+                    s.spawn(|| {
+                        dispatcher::with_default(&dispatch, || {
+                            let _span = span.enter();
+                            for _ in 0..iters {
+                                debug!(metrics.counter = "hits");
+                            }
+                        })
+                    });
+                }
+            });
+            start.elapsed()
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, events, spans, threads);
+criterion_main!(benches);
@@ -2,25 +2,21 @@ use std::thread;
 use std::time::Duration;
 
 use dipstick::{AtomicBucket, ScheduleFlush, Stream};
-use log::LevelFilter;
 use tracing::{debug, info_span, subscriber};
 use tracing_dipstick::DipstickLayer;
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::Registry;
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
 
 fn main() {
     /*
-     * We use the log-always integration of tracing here and route that to the env logger, that has
-     * INFO enabled by default and can override by RUST_LOG to something else.
+     * The logging has INFO enabled by default and can be overridden by RUST_LOG to something else.
      *
-     * We could use tracing_subscriber::fmt, *but* the EnvFilter there unfortunately disables
+     * The filter is attached to the fmt layer only. If it was a layer on its own, it would disable
      * events/spans for the whole stack, not for logging only. And we want all the metrics while we
      * want only certain level of events.
      */
-    env_logger::builder()
-        .filter_level(LevelFilter::Info)
-        .parse_default_env()
-        .init();
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let logging = fmt::layer().with_filter(filter);
 
     let root = AtomicBucket::new();
     root.stats(dipstick::stats_all);
@@ -28,7 +24,7 @@ fn main() {
     let _flush = root.flush_every(Duration::from_secs(5));
 
     let bridge = DipstickLayer::new(root);
-    let subscriber = Registry::default().with(bridge);
+    let subscriber = Registry::default().with(bridge).with(logging);
 
     subscriber::set_global_default(subscriber).unwrap();
 
@@ -0,0 +1,83 @@
+//! Tracking of the extremes the levels reach between flushes.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::sink::{self, Metric, Sink};
+
+/// The current value of a level and its extremes since the last flush.
+#[derive(Debug, Default)]
+struct Tracked {
+    current: AtomicIsize,
+    max: AtomicIsize,
+    min: AtomicIsize,
+}
+
+impl Tracked {
+    fn write(&self, delta: isize) {
+        let current = self.current.fetch_add(delta, Ordering::Relaxed) + delta;
+        self.max.fetch_max(current, Ordering::Relaxed);
+        self.min.fetch_min(current, Ordering::Relaxed);
+    }
+
+    /// Returns the extreme and starts a new interval from the current value.
+    fn take(&self, extreme: &AtomicIsize) -> isize {
+        let current = self.current.load(Ordering::Relaxed);
+        let result = extreme.swap(current, Ordering::Relaxed);
+        // Something might have changed the current value in the meantime.
+        let current = self.current.load(Ordering::Relaxed);
+        self.max.fetch_max(current, Ordering::Relaxed);
+        self.min.fetch_min(current, Ordering::Relaxed);
+        result
+    }
+}
+
+/// The configuration and state of tracking the level extremes, shared by all the scopes of a
+/// layer.
+#[derive(Debug)]
+pub(crate) struct Extremes {
+    pub(crate) max: bool,
+    pub(crate) min: bool,
+    /// By the full names of the levels, the same one might be reached through different scopes.
+    tracked: Mutex<HashMap<String, Arc<Tracked>>>,
+}
+
+impl Extremes {
+    pub(crate) fn new(max: bool, min: bool) -> Self {
+        Extremes {
+            max,
+            min,
+            tracked: Mutex::default(),
+        }
+    }
+
+    /// Wraps a freshly created level to track its extremes.
+    ///
+    /// The `path` is the full name of the scope the level lives in.
+    pub(crate) fn wrap<S: Sink>(&self, sink: &S, path: &str, name: &str, level: Metric) -> Metric {
+        let full = sink::join(path, name);
+        let tracked = {
+            let mut tracked = self.tracked.lock().unwrap_or_else(PoisonError::into_inner);
+            Arc::clone(tracked.entry(full).or_insert_with(|| {
+                let fresh = Arc::<Tracked>::default();
+                if self.max {
+                    let fresh = Arc::clone(&fresh);
+                    let name = format!("{}.max", name);
+                    sink.observe_on_flush(&name, move || fresh.take(&fresh.max));
+                }
+                if self.min {
+                    let fresh = Arc::clone(&fresh);
+                    let name = format!("{}.min", name);
+                    sink.observe_on_flush(&name, move || fresh.take(&fresh.min));
+                }
+                fresh
+            }))
+        };
+        Metric::new(move |value, labels| {
+            // Tracked as integers, the same as with the observed gauges.
+            tracked.write(value.as_i64() as isize);
+            level.write(value, labels);
+        })
+    }
+}
@@ -0,0 +1,156 @@
+//! A [`Sink`] feeding the [`metrics`] facade.
+
+use metrics::{counter, gauge, histogram, Label};
+
+use crate::sink::{Label as SpanLabel, Metric, MetricKind, ScopePath, Sink};
+
+/// Provides the handle for the labels of a written value.
+///
+/// Without any labels, the handle registered up front is used. Otherwise, the labels are added to
+/// the ones of the scope and the handle is looked up in the recorder.
+fn labeled<H: Clone>(
+    cached: &H,
+    scope: &[Label],
+    labels: &[SpanLabel],
+    register: impl FnOnce(Vec<Label>) -> H,
+) -> H {
+    if labels.is_empty() {
+        return cached.clone();
+    }
+    let labels = labels
+        .iter()
+        .map(|label| Label::new(label.key(), label.value().to_owned()));
+    register(scope.iter().cloned().chain(labels).collect())
+}
+
+/// A [`Sink`] sending the metrics into the [`metrics`] facade crate.
+///
+/// The metrics are registered with the recorder that is current at the time they are first used
+/// (the global one or a local one, see [`metrics::with_local_recorder`]).
+///
+/// The metrics are mapped like this:
+///
+/// * Counters and markers become [counters][metrics::Counter]. As these are monotonic, negative
+///   values are ignored. They hold integers, floating point values are rounded.
+/// * Gauges become [gauges][metrics::Gauge], [set][metrics::Gauge::set] to the value.
+/// * Levels become [gauges][metrics::Gauge], [incremented][metrics::Gauge::increment] by the
+///   value.
+/// * Timers become [histograms][metrics::Histogram], recording the time in seconds.
+///
+/// The labels from the `metrics.label.*` attributes are added to the labels of the metrics. Each
+/// combination of them is looked up in the recorder on every write, which is slower than writing
+/// into metrics without them.
+///
+/// The facade has no notion of flushing, so [`observe_on_flush`][Sink::observe_on_flush] does
+/// nothing.
+///
+/// Available with the `metrics` feature.
+///
+/// # Scopes
+///
+/// The full names of the scopes are prefixed to the names of the metrics, as described in
+/// [`Sink::scope`], unless [`scope_label`][MetricsFacade::scope_label] moves them to a label.
+///
+/// # Examples
+///
+/// ```rust
+/// use metrics_util::debugging::DebuggingRecorder;
+/// use tracing::{debug, info_span, subscriber};
+/// use tracing_dipstick::{DipstickLayer, MetricsFacade};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// DebuggingRecorder::new().install().unwrap();
+///
+/// let bridge = DipstickLayer::new(MetricsFacade::new().scope_label("scope"));
+/// let subscriber = Registry::default().with(bridge);
+/// subscriber::set_global_default(subscriber).unwrap();
+///
+/// let _span = info_span!("Yak", metrics.scope = "yak", metrics.timer = "time").entered();
+/// debug!(metrics.counter = "shaved");
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MetricsFacade {
+    path: ScopePath<String>,
+}
+
+impl MetricsFacade {
+    /// Creates the sink, with scopes prefixed to the names.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Puts the scopes into a label of the given key instead of the names.
+    ///
+    /// For example, `shaving.yak.time` becomes `time` with the label `scope=shaving.yak` (with the
+    /// key `scope`). Metrics outside of any scope have no such label.
+    pub fn scope_label<K: Into<String>>(self, key: K) -> Self {
+        MetricsFacade {
+            path: self.path.with_key(key.into()),
+        }
+    }
+
+    fn key(&self, name: &str) -> (String, Vec<Label>) {
+        let (name, label) = self.path.key(name);
+        let labels = label.map(|(key, path)| Label::new(key, path));
+        (name, labels.into_iter().collect())
+    }
+}
+
+impl Sink for MetricsFacade {
+    fn new_metric(&self, name: &str, kind: MetricKind) -> Metric {
+        let (name, scope) = self.key(name);
+        match kind {
+            MetricKind::Counter | MetricKind::Marker => {
+                let counter = counter!(name.clone(), scope.clone());
+                Metric::new(move |value, labels| {
+                    if let Ok(value) = u64::try_from(value.as_i64()) {
+                        labeled(&counter, &scope, labels, |labels| {
+                            counter!(name.clone(), labels)
+                        })
+                        .increment(value);
+                    }
+                })
+            }
+            MetricKind::Gauge => {
+                let gauge = gauge!(name.clone(), scope.clone());
+                Metric::new(move |value, labels| {
+                    labeled(&gauge, &scope, labels, |labels| {
+                        gauge!(name.clone(), labels)
+                    })
+                    .set(value.as_f64());
+                })
+            }
+            MetricKind::Level => {
+                let gauge = gauge!(name.clone(), scope.clone());
+                Metric::new(move |value, labels| {
+                    labeled(&gauge, &scope, labels, |labels| {
+                        gauge!(name.clone(), labels)
+                    })
+                    .increment(value.as_f64());
+                })
+            }
+            MetricKind::Timer => {
+                let histogram = histogram!(name.clone(), scope.clone());
+                Metric::new(move |micros, labels| {
+                    labeled(&histogram, &scope, labels, |labels| {
+                        histogram!(name.clone(), labels)
+                    })
+                    .record(micros.as_f64() / 1_000_000.0);
+                })
+            }
+        }
+    }
+
+    fn scope(&self, name: &str) -> Self {
+        MetricsFacade {
+            path: self.path.scope(name),
+        }
+    }
+
+    fn scope_full(&self, name: &str) -> Self {
+        MetricsFacade {
+            path: self.path.scope_full(name),
+        }
+    }
+}
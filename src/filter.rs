@@ -0,0 +1,73 @@
+//! Filtering of the callsites interesting for metrics.
+
+use std::sync::RwLock;
+
+use tracing_core::subscriber::Interest;
+use tracing_core::Metadata;
+use tracing_subscriber::layer::{Context, Filter};
+
+use crate::plan::CallsiteMap;
+use crate::{has_metrics, read, write};
+
+/// A per-layer [`Filter`] enabling exactly the spans and events that carry metrics.
+///
+/// A callsite is enabled if it has any of the `metrics.*` attributes, including the scopes (spans
+/// with only `metrics.scope` need to be enabled so the nesting works). Everything else is disabled.
+/// The decision is made once for each callsite, when it is registered. Later checks (when the
+/// other layers are interested in the callsite only sometimes) just look it up.
+///
+/// This is meant to be attached to the [`DipstickLayer`][crate::DipstickLayer] (through
+/// [`Layer::with_filter`][tracing_subscriber::Layer::with_filter]). The metrics on spans and
+/// events of any level are then collected, while other callsites of verbose levels stay disabled
+/// (unless some other layer wants them), which makes them cheap.
+///
+/// # Examples
+///
+/// ```rust
+/// use dipstick::AtomicBucket;
+/// use tracing::{subscriber, trace};
+/// use tracing_dipstick::{DipstickLayer, MetricsCallsiteFilter};
+/// use tracing_subscriber::filter::LevelFilter;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::{fmt, Layer, Registry};
+///
+/// let bridge = DipstickLayer::new(AtomicBucket::new()).with_filter(MetricsCallsiteFilter::new());
+/// let logging = fmt::layer().with_filter(LevelFilter::INFO);
+/// let subscriber = Registry::default().with(bridge).with(logging);
+///
+/// subscriber::with_default(subscriber, || {
+///     // Collected as a metric, even though it is not logged.
+///     trace!(metrics.counter = "polls", "Polled");
+///     // Neither logged, nor seen by the bridge.
+///     trace!("Something verbose");
+/// });
+/// ```
+#[derive(Debug, Default)]
+pub struct MetricsCallsiteFilter {
+    /// The decisions about the registered callsites.
+    callsites: RwLock<CallsiteMap<bool>>,
+}
+
+impl MetricsCallsiteFilter {
+    /// Creates the filter.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S> Filter<S> for MetricsCallsiteFilter {
+    fn enabled(&self, metadata: &Metadata, _: &Context<S>) -> bool {
+        let known = read(&self.callsites).get(&metadata.callsite()).copied();
+        known.unwrap_or_else(|| has_metrics(metadata))
+    }
+
+    fn callsite_enabled(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let enabled = has_metrics(metadata);
+        write(&self.callsites).insert(metadata.callsite(), enabled);
+        if enabled {
+            Interest::always()
+        } else {
+            Interest::never()
+        }
+    }
+}
@@ -0,0 +1,49 @@
+//! Detection of levels left incremented.
+
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+
+/// A level incremented through `metrics.level.inc` that wasn't decremented by the time the span
+/// owning it closed.
+///
+/// See [`DipstickLayer::level_leaks`][crate::DipstickLayer::level_leaks].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct LevelLeak<'a> {
+    /// The name of the span that owned the increments.
+    pub span: &'static str,
+    /// The full name of the scope the level lives in (empty for the root scope).
+    pub scope: &'a str,
+    /// The name of the level (without the scope prefix).
+    pub level: &'a str,
+    /// How many increments are missing their decrements.
+    pub unpaired: i64,
+}
+
+impl Display for LevelLeak<'_> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("Level ")?;
+        if !self.scope.is_empty() {
+            write!(fmt, "{}.", self.scope)?;
+        }
+        write!(
+            fmt,
+            "{} incremented {} more times than decremented in span {}",
+            self.level, self.unpaired, self.span
+        )
+    }
+}
+
+/// A detector of the leaked levels, see
+/// [`DipstickLayer::level_leaks`][crate::DipstickLayer::level_leaks].
+pub type LeakDetector = Arc<dyn Fn(&LevelLeak) + Send + Sync>;
+
+/// The configured detector, to keep the layer [`Debug`].
+#[derive(Clone)]
+pub(crate) struct Detector(pub(crate) LeakDetector);
+
+impl Debug for Detector {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("Detector")
+    }
+}
@@ -4,8 +4,9 @@
 //! tiresome to set up. Using [`tracing`] offers an opportunity to use single instrumentation and
 //! export as both.
 //!
-//! This crate exports metrics through the [`dipstick`] metrics library, provided the
-//! instrumentation uses specific attributes to events and spans. To use it:
+//! This crate exports metrics through the [`dipstick`] metrics library (or another backend
+//! implementing the [`Sink`]), provided the instrumentation uses specific attributes to events and
+//! spans. To use it:
 //!
 //! * Register the [`DipstickLayer`] to consume the spans and events.
 //! * Use the `metrics.scope` on spans to create hierarchy of the metrics.
@@ -16,39 +17,105 @@
 //!
 //! Whenever there's a span or event with one of these attributes, a metric is collected whenever
 //! it is encountered. The value of the attribute is the name of the metric and the type (the thing
-//! after the `metrics.`) corresponds to the metric types in [`dipstick`]'s [`InputScope`]. Spans
+//! after the `metrics.`) corresponds to the metric types in [`dipstick`]'s
+//! [`InputScope`][dipstick::InputScope] (see [`MetricKind`]). Spans
 //! happen when they are created and some effects happen when they are closed/destroyed.
 //!
 //! * `metrics.counter="name"`: Adds 1 to the metric counter called `name`.
+//! * `metrics.counter.on_close="name"`: Adds 1 to the counter when the span closes, no matter if
+//!   it finished its work, was cancelled (eg. the future holding it was dropped) or unwound by a
+//!   panic. Comparing it with a counter on the creation of the span shows how many spans are still
+//!   open, eg. stuck. This is accepted only on spans.
 //! * `metrics.level="name"`: Adds 1 to the level called `name`. If it is present on a span, the 1
-//!   is subtracted when it is closed (it's more useful on spans).
+//!   is subtracted when it is closed (it's more useful on spans). The highest and lowest values
+//!   reached between flushes can be published too (see [`DipstickLayer::level_max`]).
+//! * `metrics.level.inc="name"`, `metrics.level.dec="name"`: Add or subtract 1 from the level
+//!   called `name`. Unlike the above, these are meant for events and are paired ‒ the increment
+//!   is owned by the nearest enclosing span with metrics and the decrement settles an increment of
+//!   the same level (the same name in the same scope) in that span or its ancestors. Increments
+//!   left unsettled when the owning span closes can be reported (see
+//!   [`DipstickLayer::level_leaks`]), but are not undone.
 //! * `metrics.gauge="name"`: Sets the gauge to 1. This one is more useful in the second form
 //!   below.
+//! * `metrics.marker="name"`: Marks an occurrence of something in the marker called `name`. Unlike
+//!   the counter, it carries no value, which some backends treat differently.
 //! * `metrics.timer="name"`: Records the time between the creation of the span and its destruction.
 //!   This attribute is accepted only on spans.
+//! * `metrics.cpu_timer="name"`: Records the CPU time the threads consumed while inside the span
+//!   (between entering and exiting it, possibly multiple times and on different threads). It is
+//!   sent when the span is closed. Together with the above, it tells apart spans that compute from
+//!   spans that wait. This is accepted only on spans and measures nothing on platforms other than
+//!   unix and windows.
+//! * `metrics.timer.busy="name"`: Records the time during which the span was entered (on any
+//!   thread), sent when it is closed. Unlike the plain timer, an async span waiting for IO for
+//!   a long time and being polled only briefly reports the brief time.
+//! * `metrics.timer.idle="name"`: The complement of the above ‒ the time of the span's life it
+//!   wasn't entered. These are accepted only on spans.
+//! * `metrics.timer.queue="name"`: Records the time between the creation of the span and the first
+//!   time it is entered. This is sent already when entering it (and not at all if the span is
+//!   never entered). Useful for spans of work items that are created when they are enqueued and
+//!   entered when a worker picks them up. Accepted only on spans.
+//! * `metrics.timer.self="name"`: Like `metrics.timer`, but the time of the directly nested spans
+//!   with their own timers (either of these two) is subtracted. It shows where the time is spent
+//!   in a tree of scopes. Nested spans closed after the parent are not subtracted and children
+//!   running concurrently may add up to more than the parent (the result is clamped to zero).
+//!   Accepted only on spans.
 //! * `metrics.scope="scope-name"`: Names of metrics that are inside this span get prefixed by this
 //!   name, eg. their names will be `scope-name.name`. Nested spans with this attributes accumulate
 //!   the name, eg `outer-scope-name.inner-scope-name.name`. This is accepted on spans only.
 //! * `metrics.scope.full="scope-name"`: Similar to the above, but the name is not nested, it is
 //!   replaced.
+//! * `metrics.label.key=value`: Attaches the label `key` with the given value to the metrics
+//!   inside this span (including the ones of the span itself), eg. for breaking them down per
+//!   tenant. Nested spans inherit the labels and may add more or override them by using the same
+//!   key. The labels are passed to the [`Sink`], for [`dipstick`] as the per-metric
+//!   [`Labels`][dipstick::Labels]. This is accepted on spans only and can't be recorded later.
 //!
 //! The `counter`, `level` and `gauge` accept alternative variant of `metrics.type.name=value` (for
-//! example, `metrics.gauge.name=42`), which uses the given value instead of `1`.
+//! example, `metrics.gauge.name=42`), which uses the given value instead of `1`. The value may also
+//! be a floating point number. It is passed to the [`Sink`] as such, the [`dipstick`] scopes round
+//! it (see [`Scaled`]).
+//!
+//! The attributes may also be declared as [`field::Empty`][tracing_core::field::Empty] on a span
+//! and filled in later through `Span::record`. Counters and gauges are sent at the time of the
+//! recording, timers start measuring from that point and a late `metrics.scope` applies to metrics
+//! (and child spans) created after it. Recording a level of a span again replaces the previous
+//! value ‒ the level is adjusted by the difference, so only the last recorded value is subtracted
+//! when the span is closed.
+//!
+//! The effects at the end of a span (decrementing levels, sending timers) happen when it is closed.
+//! That is when the last handle to it (including its clones, possibly on other threads) is
+//! dropped, no matter through which handle it was entered. This happens during panics too, as the
+//! handles are dropped when unwinding. Only spans that are leaked (eg. through
+//! [`mem::forget`][std::mem::forget]) are never closed.
+//!
+//! The nesting of scopes follows the parents of spans and events. An explicit parent (eg.
+//! `info_span!(parent: &other, ...)`) is taken into account, and so are root spans and events
+//! created with `parent: None`. The currently entered span is used only for contextual ones.
 //!
 //! Unfortunately, typos don't cause compile errors, they are just ignored :-(.
 //!
 //! # Naming
 //!
-//! While the metrics are sent into the [`dipstick`] library, the attribute naming is quite
-//! general. This is on purpose. The author envisions that other crates might offer similar
+//! While the metrics are sent into the [`dipstick`] library by default, the attribute naming is
+//! quite general. This is on purpose. The author envisions that other crates might offer similar
 //! functionality, but export the metrics to a different library. In such case it is beneficial if
 //! the attributes are the same ‒ in such case changing the "backend" means only different
 //! initialization while the instrumentation of the whole code stays the same.
 //!
+//! Within this crate, the backend is abstracted by the [`Sink`] trait. It is implemented for the
+//! [`dipstick`] scopes, but the [`DipstickLayer`] accepts any other implementation too. With the
+//! `metrics` feature, the `MetricsFacade` sink feeds the [`metrics`](https://docs.rs/metrics)
+//! facade. With the `opentelemetry` feature, the `OtelMeter` sink creates
+//! [OpenTelemetry](https://docs.rs/opentelemetry) instruments.
+//! And with the `prometheus` feature, the `PrometheusRegistry` keeps the metrics in memory, to
+//! be scraped by Prometheus.
+//!
 //! # Crate status
 //!
-//! * There are some limitations about filtering (see the note at [`DipstickLayer`]). They may be
-//!   fixed either in [`tracing_subscriber`] or by changes in here, but both needs some work.
+//! * The filtering of other layers needs to be done on per-layer basis (see the note at
+//!   [`DipstickLayer`]), otherwise it starves the metrics. Alternatively, the
+//!   [`DipstickSubscriber`] wraps a filtering subscriber.
 //! * There are several performance inefficiencies that need to be eliminated.
 //! * The crate has been tested only lightly and it's possible it might not act correctly in some
 //!   corner cases.
@@ -63,25 +130,22 @@
 //! use std::time::Duration;
 //!
 //! use dipstick::{AtomicBucket, ScheduleFlush, Stream};
-//! use log::LevelFilter;
 //! use tracing::{debug, info_span, subscriber};
 //! use tracing_dipstick::DipstickLayer;
 //! use tracing_subscriber::layer::SubscriberExt;
-//! use tracing_subscriber::Registry;
+//! use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
 //!
 //! fn main() {
 //!     /*
-//!      * We use the log-always integration of tracing here and route that to the env logger, that has
-//!      * INFO enabled by default and can override by RUST_LOG to something else.
+//!      * The logging has INFO enabled by default and can be overridden by RUST_LOG to something
+//!      * else.
 //!      *
-//!      * We could use tracing_subscriber::fmt, *but* the EnvFilter there unfortunately disables
-//!      * events/spans for the whole stack, not for logging only. And we want all the metrics while we
-//!      * want only certain level of events.
+//!      * The filter is attached to the fmt layer only. If it was a layer on its own, it would
+//!      * disable events/spans for the whole stack, not for logging only. And we want all the
+//!      * metrics while we want only certain level of events.
 //!      */
-//!     env_logger::builder()
-//!         .filter_level(LevelFilter::Info)
-//!         .parse_default_env()
-//!         .init();
+//!     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+//!     let logging = fmt::layer().with_filter(filter);
 //!
 //!     let root = AtomicBucket::new();
 //!     root.stats(dipstick::stats_all);
@@ -89,7 +153,7 @@
 //!     let _flush = root.flush_every(Duration::from_secs(5));
 //!
 //!     let bridge = DipstickLayer::new(root);
-//!     let subscriber = Registry::default().with(bridge);
+//!     let subscriber = Registry::default().with(bridge).with(logging);
 //!
 //!     subscriber::set_global_default(subscriber).unwrap();
 //!
@@ -117,48 +181,188 @@
 #![forbid(unsafe_code)]
 #![warn(missing_docs)]
 
+use std::collections::HashMap;
 use std::fmt::Debug;
+use std::hash::BuildHasherDefault;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
 
-use dipstick::{InputScope, Level, Prefixed, TimeHandle, Timer};
-use once_cell::unsync::Lazy;
+use dipstick::TimeHandle;
+use tracing_core::callsite::Identifier;
 use tracing_core::field::{Field, Visit};
-use tracing_core::span::{Attributes, Id};
-use tracing_core::{Event, Subscriber};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::subscriber::Interest;
+use tracing_core::{Event, Metadata, Subscriber};
 use tracing_subscriber::layer::{Context, Layer};
-use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::registry::{Extensions, ExtensionsMut, LookupSpan, SpanRef};
+
+mod extremes;
+#[cfg(feature = "metrics")]
+mod facade;
+mod filter;
+mod leak;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod plan;
+#[cfg(feature = "prometheus")]
+mod prometheus;
+mod sink;
+mod subscriber;
+mod timing;
+
+#[cfg(feature = "metrics")]
+pub use facade::MetricsFacade;
+pub use filter::MetricsCallsiteFilter;
+pub use leak::{LeakDetector, LevelLeak};
+#[cfg(feature = "opentelemetry")]
+pub use otel::OtelMeter;
+#[cfg(feature = "prometheus")]
+pub use prometheus::PrometheusRegistry;
+pub use sink::{Label, Labels, Metric, MetricKind, MetricValue, Scaled, Sink};
+pub use subscriber::DipstickSubscriber;
+
+use extremes::Extremes;
+use leak::Detector;
+use plan::{CallsiteHasher, FieldPlan, Plan, Plans};
+use timing::{BusyTime, CpuTime};
 
 const SCOPE_NAME: &str = "metrics.scope";
 const SCOPE_NAME_FULL: &str = "metrics.scope.full";
+const LABEL_PREFIX: &str = "metrics.label.";
+/// How many derived scopes are cached in each scope.
+///
+/// Protects against unbounded growth if the scope names are generated (eg. contain ids). Scopes
+/// over the limit are still derived, but anew each time.
+const MAX_CHILDREN: usize = 256;
+/// How many metrics named by values are cached in each scope.
+///
+/// The same as with [`MAX_CHILDREN`], the names may be generated (eg. contain ids). Metrics over
+/// the limit are created anew on each use, without being kept around.
+const MAX_VALUES: usize = 256;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum MetricType {
     Counter,
+    ClosedCounter,
     Gauge,
     Level,
+    LevelInc,
+    LevelDec,
+    Marker,
     Timer,
+    CpuTimer,
+    BusyTimer,
+    IdleTimer,
+    QueueTimer,
+    SelfTimer,
 }
 
 impl MetricType {
-    fn measure<P: MetricPoint>(self, point: &mut P, name: &str, value: i64) {
-        let scope = point.scope();
+    /// Is this a timer measured from what happens during the life of the span (entering and
+    /// exiting it)?
+    fn is_tracked(self) -> bool {
+        matches!(
+            self,
+            MetricType::CpuTimer
+                | MetricType::BusyTimer
+                | MetricType::IdleTimer
+                | MetricType::QueueTimer
+        )
+    }
+
+    fn kind(self) -> MetricKind {
         match self {
-            MetricType::Counter => scope.counter(name).count(value as _),
-            MetricType::Gauge => scope.gauge(name).value(value),
-            MetricType::Level => {
-                let level = scope.level(name);
-                level.adjust(value);
-                point.push_level(level, value);
+            MetricType::Counter | MetricType::ClosedCounter => MetricKind::Counter,
+            MetricType::Gauge => MetricKind::Gauge,
+            MetricType::Level | MetricType::LevelInc | MetricType::LevelDec => MetricKind::Level,
+            MetricType::Marker => MetricKind::Marker,
+            MetricType::Timer
+            | MetricType::CpuTimer
+            | MetricType::BusyTimer
+            | MetricType::IdleTimer
+            | MetricType::QueueTimer
+            | MetricType::SelfTimer => MetricKind::Timer,
+        }
+    }
+
+    /// The type the metric is resolved as.
+    fn resolved(self) -> MetricType {
+        match self {
+            MetricType::LevelInc | MetricType::LevelDec => MetricType::Level,
+            MetricType::ClosedCounter => MetricType::Counter,
+            tp => tp,
+        }
+    }
+
+    fn measure<P: MetricPoint>(
+        self,
+        point: &mut P,
+        field: &Field,
+        name: MetricName,
+        value: MetricValue,
+    ) {
+        let labels = point.labels();
+        let kept = point
+            .node()
+            .with_metric(self.resolved(), name, |metric| match self {
+                MetricType::Counter | MetricType::Gauge => {
+                    metric.write(value, labels);
+                    None
+                }
+                MetricType::Marker => {
+                    metric.write(1, labels);
+                    None
+                }
+                MetricType::Level => {
+                    metric.write(value, labels);
+                    Some(metric.clone())
+                }
+                MetricType::ClosedCounter => Some(metric.clone()),
+                MetricType::LevelInc => {
+                    metric.write(1, labels);
+                    None
+                }
+                MetricType::LevelDec => {
+                    metric.write(-1, labels);
+                    None
+                }
+                MetricType::Timer
+                | MetricType::CpuTimer
+                | MetricType::BusyTimer
+                | MetricType::IdleTimer
+                | MetricType::QueueTimer
+                | MetricType::SelfTimer => Some(metric.clone()),
+            });
+        match (self, kept) {
+            (MetricType::Level, Some(level)) => point.push_level(field, level, value),
+            (MetricType::ClosedCounter, Some(counter)) => point.push_closed(field, counter),
+            (MetricType::Timer | MetricType::SelfTimer, Some(timer)) => {
+                point.push_timer(field, self, timer, TimeHandle::now())
             }
-            MetricType::Timer => {
-                let timer = scope.timer(name);
-                let start = timer.start();
-                point.push_timer(timer, start);
+            (tp, Some(timer)) if tp.is_tracked() => point.push_tracked(field, tp, timer),
+            (MetricType::LevelInc | MetricType::LevelDec, _) => {
+                let level = match name {
+                    MetricName::Field(_, name) => name,
+                    MetricName::Value(_, name) => name,
+                };
+                let delta = if self == MetricType::LevelInc { 1 } else { -1 };
+                let scope = point.node().path.clone();
+                point.push_paired(scope, level, delta);
             }
+            _ => (),
         }
     }
 }
 
 const METRIC_TYPES: &[(&str, &str, MetricType, bool)] = &[
+    // This needs to go before the counter, it'd be taken as its value form otherwise.
+    (
+        "metrics.counter.on_close",
+        "",
+        MetricType::ClosedCounter,
+        false,
+    ),
     (
         "metrics.counter",
         "metrics.counter.",
@@ -166,116 +370,656 @@ const METRIC_TYPES: &[(&str, &str, MetricType, bool)] = &[
         true,
     ),
     ("metrics.gauge", "metrics.gauge.", MetricType::Gauge, true),
+    // These need to go before the level, they'd be taken as its value form otherwise.
+    ("metrics.level.inc", "", MetricType::LevelInc, true),
+    ("metrics.level.dec", "", MetricType::LevelDec, true),
     ("metrics.level", "metrics.level.", MetricType::Level, true),
+    ("metrics.marker", "", MetricType::Marker, true),
     ("metrics.timer", "", MetricType::Timer, false),
+    ("metrics.cpu_timer", "", MetricType::CpuTimer, false),
+    ("metrics.timer.busy", "", MetricType::BusyTimer, false),
+    ("metrics.timer.idle", "", MetricType::IdleTimer, false),
+    ("metrics.timer.queue", "", MetricType::QueueTimer, false),
+    ("metrics.timer.self", "", MetricType::SelfTimer, false),
 ];
 
+/// Checks if a field of this name is one of the attributes this crate recognizes.
+fn is_metric_field(name: &str) -> bool {
+    name == SCOPE_NAME
+        || name == SCOPE_NAME_FULL
+        || name.starts_with(LABEL_PREFIX)
+        || METRIC_TYPES
+            .iter()
+            .any(|tp| name == tp.0 || (!tp.1.is_empty() && name.starts_with(tp.1)))
+}
+
+/// Checks if the callsite has any fields interesting for metrics.
+fn has_metrics(metadata: &Metadata) -> bool {
+    metadata
+        .fields()
+        .iter()
+        .any(|field| is_metric_field(field.name()))
+}
+
+/// Locks for reading, ignoring poisoning.
+///
+/// The locked data are caches that stay consistent even if something (usually the metrics backend)
+/// panics while they are locked. Such panic must not break all the metrics from then on.
+fn read<T>(lock: &RwLock<T>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Locks for writing, ignoring poisoning (see [`read`]).
+fn write<T>(lock: &RwLock<T>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// How a metric is named.
+#[derive(Copy, Clone)]
+enum MetricName<'a> {
+    /// By the name of the field (the part after the type).
+    Field(&'a Field, &'static str),
+    /// By the value of the field.
+    Value(&'a Field, &'a str),
+}
+
+/// A map keyed by fields of callsites.
+type FieldMap<V> = HashMap<(Identifier, usize), V, BuildHasherDefault<CallsiteHasher>>;
+
+/// The metrics already resolved in a [`ScopeNode`].
+#[derive(Debug, Default)]
+struct Resolved {
+    /// The ones named by fields, indexed by the callsite and index of the field.
+    fields: FieldMap<Metric>,
+    /// The first metric named by the value of each field.
+    ///
+    /// The value is usually a literal, so this saves hashing it on every use. It is compared with
+    /// the cached name, other names go through `values`. Only the names kept in `values` are
+    /// cached here.
+    named: FieldMap<(Box<str>, Metric)>,
+    /// The ones named by values, up to [`MAX_VALUES`] names.
+    values: HashMap<String, Vec<(MetricType, Metric)>>,
+}
+
+/// The scopes derived from a [`ScopeNode`] by their names.
+#[derive(Debug)]
+struct Children<S> {
+    /// Through `metrics.scope`.
+    nested: HashMap<String, Arc<ScopeNode<S>>>,
+    /// Through `metrics.scope.full`.
+    full: HashMap<String, Arc<ScopeNode<S>>>,
+}
+
+impl<S> Children<S> {
+    fn get(&self, full: bool) -> &HashMap<String, Arc<ScopeNode<S>>> {
+        if full {
+            &self.full
+        } else {
+            &self.nested
+        }
+    }
+
+    fn get_mut(&mut self, full: bool) -> &mut HashMap<String, Arc<ScopeNode<S>>> {
+        if full {
+            &mut self.full
+        } else {
+            &mut self.nested
+        }
+    }
+}
+
+impl<S> Default for Children<S> {
+    fn default() -> Self {
+        Children {
+            nested: HashMap::new(),
+            full: HashMap::new(),
+        }
+    }
+}
+
+/// A dipstick scope, together with the metrics already resolved in it and the scopes derived from
+/// it.
+///
+/// Creating a metric or prefixing the scope is relatively expensive, therefore they are cached
+/// here.
+#[derive(Debug)]
+struct ScopeNode<S> {
+    scope: S,
+    resolved: RwLock<Resolved>,
+    children: RwLock<Children<S>>,
+    /// The full name of the scope (without the prefix of the root).
+    path: String,
+    /// Shared by all the nodes of the layer, if turned on.
+    extremes: Option<Arc<Extremes>>,
+}
+
+impl<S: Sink> ScopeNode<S> {
+    fn new(scope: S, path: String, extremes: Option<Arc<Extremes>>) -> Self {
+        ScopeNode {
+            scope,
+            resolved: RwLock::default(),
+            children: RwLock::default(),
+            path,
+            extremes,
+        }
+    }
+
+    fn new_metric(&self, tp: MetricType, name: &str) -> Metric {
+        let metric = self.scope.new_metric(name, tp.kind());
+        match (tp, &self.extremes) {
+            (MetricType::Level, Some(extremes)) => {
+                extremes.wrap(&self.scope, &self.path, name, metric)
+            }
+            _ => metric,
+        }
+    }
+
+    /// Provides the scope derived from this one by a `metrics.scope` (or `metrics.scope.full` if
+    /// `full`).
+    fn child(&self, full: bool, name: &str) -> Arc<ScopeNode<S>> {
+        if let Some(child) = read(&self.children).get(full).get(name) {
+            return Arc::clone(child);
+        }
+
+        let derive = || {
+            let (scope, path) = if full {
+                (self.scope.scope_full(name), name.to_owned())
+            } else {
+                (self.scope.scope(name), sink::join(&self.path, name))
+            };
+            Arc::new(ScopeNode::new(scope, path, self.extremes.clone()))
+        };
+        let mut children = write(&self.children);
+        let cached = children.get_mut(full);
+        if let Some(child) = cached.get(name) {
+            Arc::clone(child)
+        } else if cached.len() < MAX_CHILDREN {
+            Arc::clone(cached.entry(name.to_owned()).or_insert_with(derive))
+        } else {
+            derive()
+        }
+    }
+
+    /// Provides the metric, creating it on first use.
+    ///
+    /// The common case takes only a read lock, looks up the callsite and field with a cheap hash
+    /// and, for metrics named by values, compares the value with the cached name. The lock stays,
+    /// as the metrics are created lazily and shared by all the threads using the scope; it is
+    /// taken for writing only when a metric is created.
+    fn with_metric<R>(&self, tp: MetricType, name: MetricName, f: impl FnOnce(&Metric) -> R) -> R {
+        {
+            let resolved = read(&self.resolved);
+            let found = match name {
+                MetricName::Field(field, _) => {
+                    resolved.fields.get(&(field.callsite(), field.index()))
+                }
+                MetricName::Value(field, value) => {
+                    match resolved.named.get(&(field.callsite(), field.index())) {
+                        Some((cached, metric)) if **cached == *value => Some(metric),
+                        _ => resolved
+                            .values
+                            .get(value)
+                            .and_then(|metrics| metrics.iter().find(|(t, _)| *t == tp))
+                            .map(|(_, metric)| metric),
+                    }
+                }
+            };
+            if let Some(metric) = found {
+                return f(metric);
+            }
+        }
+
+        let mut resolved = write(&self.resolved);
+        let metric = match name {
+            MetricName::Field(field, name) => resolved
+                .fields
+                .entry((field.callsite(), field.index()))
+                .or_insert_with(|| self.new_metric(tp, name))
+                .clone(),
+            // Too many names already, this one is not kept.
+            MetricName::Value(_, value)
+                if resolved.values.len() >= MAX_VALUES && !resolved.values.contains_key(value) =>
+            {
+                self.new_metric(tp, value)
+            }
+            MetricName::Value(field, value) => {
+                let metrics = resolved.values.entry(value.to_owned()).or_default();
+                let metric = match metrics.iter().find(|(t, _)| *t == tp) {
+                    Some((_, metric)) => metric.clone(),
+                    None => {
+                        let metric = self.new_metric(tp, value);
+                        metrics.push((tp, metric.clone()));
+                        metric
+                    }
+                };
+                resolved
+                    .named
+                    .entry((field.callsite(), field.index()))
+                    .or_insert_with(|| (value.into(), metric.clone()));
+                metric
+            }
+        };
+        drop(resolved);
+        f(&metric)
+    }
+}
+
 trait MetricPoint {
-    const SCOPED: bool;
-    type Scope: InputScope;
-    fn push_timer(&mut self, timer: Timer, start: TimeHandle);
-    fn push_level(&mut self, level: Level, decrement: i64);
-    fn scope(&self) -> &Self::Scope;
+    type Scope: Sink;
+    fn push_timer(&mut self, field: &Field, tp: MetricType, timer: Metric, start: TimeHandle);
+    fn push_tracked(&mut self, field: &Field, tp: MetricType, timer: Metric);
+    fn push_level(&mut self, field: &Field, level: Metric, decrement: MetricValue);
+    fn push_closed(&mut self, field: &Field, counter: Metric);
+    fn push_paired(&mut self, scope: String, level: &str, delta: i64);
+    fn node(&self) -> &ScopeNode<Self::Scope>;
+    fn labels(&self) -> &Labels;
 }
 
-struct PointWrap<P>(P);
+impl<P: MetricPoint> MetricPoint for &mut P {
+    type Scope = P::Scope;
+    fn push_timer(&mut self, field: &Field, tp: MetricType, timer: Metric, start: TimeHandle) {
+        (**self).push_timer(field, tp, timer, start);
+    }
+    fn push_tracked(&mut self, field: &Field, tp: MetricType, timer: Metric) {
+        (**self).push_tracked(field, tp, timer);
+    }
+    fn push_level(&mut self, field: &Field, level: Metric, decrement: MetricValue) {
+        (**self).push_level(field, level, decrement);
+    }
+    fn push_closed(&mut self, field: &Field, counter: Metric) {
+        (**self).push_closed(field, counter);
+    }
+    fn push_paired(&mut self, scope: String, level: &str, delta: i64) {
+        (**self).push_paired(scope, level, delta);
+    }
+    fn node(&self) -> &ScopeNode<P::Scope> {
+        (**self).node()
+    }
+    fn labels(&self) -> &Labels {
+        (**self).labels()
+    }
+}
 
-impl<P: MetricPoint> Visit for PointWrap<P> {
+/// Visits the fields, measuring the metrics into `P` according to the plan of the callsite.
+struct PointWrap<'a, P> {
+    point: P,
+    plan: &'a Plan,
+}
+
+impl<P: MetricPoint> PointWrap<'_, P> {
+    fn record_value(&mut self, field: &Field, value: MetricValue) {
+        if let Some(FieldPlan::Valued(tp, name)) = self.plan.get(field) {
+            tp.measure(
+                &mut self.point,
+                field,
+                MetricName::Field(field, name),
+                value,
+            );
+        }
+    }
+}
+
+impl<P: MetricPoint> Visit for PointWrap<'_, P> {
     fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
     fn record_str(&mut self, field: &Field, value: &str) {
-        let name = field.name();
-        for tp in METRIC_TYPES {
-            if (tp.3 || P::SCOPED) && name == tp.0 {
-                tp.2.measure(&mut self.0, value, 1);
-                break;
-            }
+        if let Some(FieldPlan::Named(tp)) = self.plan.get(field) {
+            let name = MetricName::Value(field, value);
+            tp.measure(&mut self.point, field, name, MetricValue::Int(1));
         }
     }
 
     fn record_i64(&mut self, field: &Field, value: i64) {
-        let name = field.name();
-        for tp in METRIC_TYPES {
-            if tp.3 && name.starts_with(tp.1) {
-                tp.2.measure(&mut self.0, &name[tp.1.len()..], value);
-            }
-        }
+        self.record_value(field, MetricValue::Int(value));
     }
     fn record_u64(&mut self, field: &Field, value: u64) {
         self.record_i64(field, value as _);
     }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        // Passed on as it is, the sinks decide how to represent it.
+        self.record_value(field, MetricValue::Float(value));
+    }
 }
 
-#[derive(Clone)]
 struct Scope<S> {
-    scope: S,
+    node: Arc<ScopeNode<S>>,
+    /// Own and inherited, shared with the child spans that don't add any.
+    labels: Labels,
     // TODO: Small vecs? Put into the same vec to save one allocation?
-    timers: Vec<(Timer, TimeHandle)>,
-    levels: Vec<(Level, i64)>,
-    // TODO: CPU timers
+    // The fields are kept to recognize the same attribute being recorded again.
+    timers: Vec<(Field, MetricType, Metric, TimeHandle)>,
+    levels: Vec<(Field, Metric, MetricValue)>,
+    /// The timers measured from entering and exiting the span, mostly sent when it closes.
+    tracked: Vec<(Field, MetricType, Metric)>,
+    /// Measured only if there are some CPU timers.
+    cpu: CpuTime,
+    busy: BusyTime,
+    /// The time spent in the directly nested timed spans, for the self timers.
+    nested: Duration,
+    /// The outstanding increments through `metrics.level.inc`, by the path of the scope and the
+    /// name of the level.
+    paired: Vec<(String, String, i64)>,
+    /// The counters incremented when the span closes.
+    closed: Vec<(Field, Metric)>,
+}
+
+impl<S> Scope<S> {
+    fn new(node: Arc<ScopeNode<S>>, labels: Labels) -> Self {
+        Scope {
+            node,
+            labels,
+            timers: Vec::new(),
+            levels: Vec::new(),
+            tracked: Vec::new(),
+            cpu: CpuTime::default(),
+            busy: BusyTime::new(),
+            nested: Duration::ZERO,
+            paired: Vec::new(),
+            closed: Vec::new(),
+        }
+    }
+
+    fn add_paired(&mut self, scope: String, level: &str) {
+        match self
+            .paired
+            .iter_mut()
+            .find(|(s, l, _)| *s == scope && l == level)
+        {
+            Some((_, _, unpaired)) => *unpaired += 1,
+            None => self.paired.push((scope, level.to_owned(), 1)),
+        }
+    }
+
+    /// Settles one increment of the level in the scope, if there's any.
+    fn settle_paired(&mut self, scope: &str, level: &str) -> bool {
+        let Some(pos) = self
+            .paired
+            .iter()
+            .position(|(s, l, _)| s == scope && l == level)
+        else {
+            return false;
+        };
+        self.paired[pos].2 -= 1;
+        if self.paired[pos].2 == 0 {
+            self.paired.swap_remove(pos);
+        }
+        true
+    }
+
+    /// Does the span measure its lifetime?
+    ///
+    /// Such spans are the boundaries for the self timers.
+    fn is_timed(&self) -> bool {
+        !self.timers.is_empty()
+    }
+
+    fn enter(&mut self) {
+        if self
+            .tracked
+            .iter()
+            .any(|(_, tp, _)| *tp == MetricType::CpuTimer)
+        {
+            self.cpu.enter();
+        }
+        self.busy.enter();
+        if let Some(queued) = self.busy.queued() {
+            let labels = &self.labels;
+            // The queue timers are sent on the first enter already.
+            self.tracked.retain(|(_, tp, timer)| {
+                if *tp == MetricType::QueueTimer {
+                    timer.write(queued.as_micros() as i64, labels);
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+    }
+
+    fn exit(&mut self) {
+        self.cpu.exit();
+        self.busy.exit();
+    }
 }
 
 impl<S> Drop for Scope<S> {
     fn drop(&mut self) {
-        for (timer, start) in self.timers.drain(..) {
-            timer.stop(start);
+        let labels = &self.labels;
+        let nested = self.nested.as_micros() as u64;
+        for (_, tp, timer, start) in self.timers.drain(..) {
+            let elapsed = match tp {
+                MetricType::SelfTimer => start.elapsed_us().saturating_sub(nested),
+                _ => start.elapsed_us(),
+            };
+            timer.write(elapsed as i64, labels);
         }
 
-        for (level, decrement) in self.levels.drain(..) {
-            level.adjust(-decrement);
+        for (_, tp, timer) in self.tracked.drain(..) {
+            let time = match tp {
+                MetricType::CpuTimer => self.cpu.spent(),
+                MetricType::BusyTimer => self.busy.busy(),
+                MetricType::IdleTimer => self.busy.idle(),
+                // Never entered, so it never left the queue.
+                MetricType::QueueTimer => continue,
+                _ => unreachable!("Not a tracked timer {:?}", tp),
+            };
+            timer.write(time.as_micros() as i64, labels);
+        }
+
+        for (_, counter) in self.closed.drain(..) {
+            counter.write(1, labels);
+        }
+
+        for (_, level, decrement) in self.levels.drain(..) {
+            level.write(-decrement, labels);
         }
     }
 }
 
-impl<S: InputScope> MetricPoint for Scope<S> {
-    const SCOPED: bool = true;
+impl<S: Sink> MetricPoint for Scope<S> {
     type Scope = S;
-    fn push_level(&mut self, level: Level, decrement: i64) {
-        self.levels.push((level, decrement));
+    fn push_level(&mut self, field: &Field, level: Metric, decrement: MetricValue) {
+        let labels = &self.labels;
+        match self.levels.iter_mut().find(|(f, _, _)| f == field) {
+            // Recorded again through Span::record ‒ the new value replaces the old one.
+            Some(old) => {
+                old.1.write(-old.2, labels);
+                *old = (field.clone(), level, decrement);
+            }
+            None => self.levels.push((field.clone(), level, decrement)),
+        }
     }
-    fn push_timer(&mut self, timer: Timer, start: TimeHandle) {
-        self.timers.push((timer, start));
+    fn push_timer(&mut self, field: &Field, tp: MetricType, timer: Metric, start: TimeHandle) {
+        match self.timers.iter_mut().find(|(f, _, _, _)| f == field) {
+            // A timer recorded again restarts the measurement.
+            Some(old) => *old = (field.clone(), tp, timer, start),
+            None => self.timers.push((field.clone(), tp, timer, start)),
+        }
     }
-    fn scope(&self) -> &S {
-        &self.scope
+    fn push_tracked(&mut self, field: &Field, tp: MetricType, timer: Metric) {
+        // The measurements are shared by the whole span and are not restarted by recording again.
+        // If recorded late, the CPU time is measured from the next enter.
+        if let (MetricType::QueueTimer, Some(queued)) = (tp, self.busy.queued()) {
+            // Already out of the queue, send right away.
+            timer.write(queued.as_micros() as i64, self.labels());
+            return;
+        }
+        match self.tracked.iter_mut().find(|(f, _, _)| f == field) {
+            Some(old) => old.2 = timer,
+            None => self.tracked.push((field.clone(), tp, timer)),
+        }
+    }
+    fn push_closed(&mut self, field: &Field, counter: Metric) {
+        // Recording again renames the counter, the span is still counted only once.
+        match self.closed.iter_mut().find(|(f, _)| f == field) {
+            Some(old) => old.1 = counter,
+            None => self.closed.push((field.clone(), counter)),
+        }
+    }
+    fn push_paired(&mut self, scope: String, level: &str, delta: i64) {
+        if delta > 0 {
+            self.add_paired(scope, level);
+        } else {
+            self.settle_paired(&scope, level);
+        }
+    }
+    fn node(&self) -> &ScopeNode<S> {
+        &self.node
+    }
+    fn labels(&self) -> &Labels {
+        &self.labels
     }
 }
 
-impl<S, F> MetricPoint for Lazy<S, F>
-where
-    S: InputScope,
-    F: FnOnce() -> S,
-{
-    const SCOPED: bool = false;
+/// The point events are measured into.
+///
+/// It borrows the node of the enclosing span. The paired levels are collected (with the paths of
+/// their scopes), to be settled with the owning spans afterwards.
+struct EventPoint<'a, S> {
+    node: &'a ScopeNode<S>,
+    labels: &'a Labels,
+    paired: Vec<(String, String, i64)>,
+}
+
+impl<S: Sink> MetricPoint for EventPoint<'_, S> {
     type Scope = S;
 
-    fn push_timer(&mut self, _: Timer, _: TimeHandle) {
+    fn push_timer(&mut self, _: &Field, _: MetricType, _: Metric, _: TimeHandle) {
         unreachable!("Timers are not supported on events");
     }
 
-    fn push_level(&mut self, _: Level, _: i64) {
+    fn push_tracked(&mut self, _: &Field, _: MetricType, _: Metric) {
+        unreachable!("Timers are not supported on events");
+    }
+
+    fn push_level(&mut self, _: &Field, _: Metric, _: MetricValue) {
         // Levels on events are decremented manually, not at the end of some scope
     }
 
-    fn scope(&self) -> &S {
-        self
+    fn push_closed(&mut self, _: &Field, _: Metric) {
+        unreachable!("Counters on close are not supported on events");
+    }
+
+    fn push_paired(&mut self, scope: String, level: &str, delta: i64) {
+        self.paired.push((scope, level.to_owned(), delta));
+    }
+
+    fn node(&self) -> &ScopeNode<S> {
+        self.node
+    }
+
+    fn labels(&self) -> &Labels {
+        self.labels
     }
 }
 
+/// The per-span states of all the [`DipstickLayer`]s with the same type of scope.
+///
+/// There may be multiple such layers in one subscriber, therefore the states are tagged by the id of
+/// the layer they belong to.
+struct Scopes<S>(Vec<(usize, Scope<S>)>);
+
+/// Source of unique ids of the [`DipstickLayer`] instances.
+static NEXT_LAYER_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Derives the scope of a span from its `metrics.scope` (or `metrics.scope.full`) attribute.
+///
+/// Returns `None` if the recorded values don't contain any of these.
+fn derived<S>(
+    parent: &ScopeNode<S>,
+    plan: &Plan,
+    record: impl FnOnce(&mut dyn Visit),
+) -> Option<Arc<ScopeNode<S>>>
+where
+    S: Sink,
+{
+    struct NameVisitor<'a, S> {
+        target: Option<Arc<ScopeNode<S>>>,
+        parent: &'a ScopeNode<S>,
+        plan: &'a Plan,
+    }
+    impl<S> Visit for NameVisitor<'_, S>
+    where
+        S: Sink,
+    {
+        fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if let Some(FieldPlan::Scope(full)) = self.plan.get(field) {
+                self.target = Some(self.parent.child(full, value));
+            }
+        }
+    }
+    let mut visitor = NameVisitor {
+        target: None,
+        parent,
+        plan,
+    };
+    record(&mut visitor);
+    visitor.target
+}
+
+/// Collects the labels of a span from its `metrics.label.*` attributes.
+///
+/// The own labels replace the inherited ones with the same key.
+fn labeled(inherited: &Labels, plan: &Plan, record: impl FnOnce(&mut dyn Visit)) -> Labels {
+    struct LabelVisitor<'a> {
+        labels: Vec<Label>,
+        plan: &'a Plan,
+    }
+    impl LabelVisitor<'_> {
+        fn add(&mut self, field: &Field, value: impl ToString) {
+            if let Some(FieldPlan::Label(key)) = self.plan.get(field) {
+                self.labels.retain(|label| label.key() != key);
+                self.labels.push(Label::new(key, value.to_string()));
+            }
+        }
+    }
+    impl Visit for LabelVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.add(field, format!("{:?}", value));
+        }
+        fn record_str(&mut self, field: &Field, value: &str) {
+            self.add(field, value);
+        }
+        fn record_i64(&mut self, field: &Field, value: i64) {
+            self.add(field, value);
+        }
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.add(field, value);
+        }
+        fn record_f64(&mut self, field: &Field, value: f64) {
+            self.add(field, value);
+        }
+        fn record_bool(&mut self, field: &Field, value: bool) {
+            self.add(field, value);
+        }
+    }
+    let mut visitor = LabelVisitor {
+        labels: inherited.to_vec(),
+        plan,
+    };
+    record(&mut visitor);
+    visitor.labels.into()
+}
+
 /// The bridge from [`tracing`](https://docs.rs/tracing) to [`dipstick`].
 ///
-/// This takes information from tracing and propagates them into [`dipstick`] as metrics. It works
-/// as [`Layer`].
+/// This takes information from tracing and propagates them into [`dipstick`] (or another
+/// [`Sink`]) as metrics. It works as [`Layer`].
 ///
-/// # Warning
+/// # Filtering
 ///
-/// Currently, [`tracing_subscriber`] doesn't allow filtering on per-layer basis. That means if
-/// there's another layer that filters (for example based on the level), it'll impact this layer
-/// too. This would negatively impact the gathered metrics as this expects to get them all.
+/// The layer expects to see all the spans and events that carry metrics. A filter that is placed
+/// into the subscriber as a layer on its own (or a filtering subscriber) disables the spans and
+/// events for the whole stack, including this layer. That would negatively impact the gathered
+/// metrics.
 ///
-/// It has been observed to work together with the `tracing`s `log-always` feature.
+/// Therefore, the filters of other layers need to be attached to them as per-layer filters, with
+/// [`Layer::with_filter`]. Then, for example, a `fmt` layer may log only the INFO events while
+/// this layer still sees the DEBUG and TRACE ones. If the spans of the enclosing scope are disabled
+/// for this layer, the nearest enabled ancestor is used.
 ///
-/// Future versions might bypass the [`Layer`] system and wrap a
-/// [`Subscriber`][tracing_core::Subscriber] directly.
+/// If that's not possible (for example because the filtering subscriber comes from elsewhere), the
+/// [`DipstickSubscriber`] can wrap it and collect the metrics independently of its filtering.
 ///
 /// # Examples
 ///
@@ -283,16 +1027,11 @@ where
 /// use std::time::Duration;
 ///
 /// use dipstick::{AtomicBucket, ScheduleFlush, Stream};
-/// use log::LevelFilter;
 /// use tracing::subscriber;
 /// use tracing_dipstick::DipstickLayer;
+/// use tracing_subscriber::filter::LevelFilter;
 /// use tracing_subscriber::layer::SubscriberExt;
-/// use tracing_subscriber::Registry;
-///
-/// env_logger::builder()
-///     .filter_level(LevelFilter::Info)
-///     .parse_default_env()
-///     .init();
+/// use tracing_subscriber::{fmt, Layer, Registry};
 ///
 /// let root = AtomicBucket::new();
 /// root.stats(dipstick::stats_all);
@@ -300,103 +1039,365 @@ where
 /// let _flush = root.flush_every(Duration::from_secs(5));
 ///
 /// let bridge = DipstickLayer::new(root);
-/// let subscriber = Registry::default().with(bridge);
+/// let logging = fmt::layer().with_filter(LevelFilter::INFO);
+/// let subscriber = Registry::default().with(bridge).with(logging);
 ///
 /// subscriber::set_global_default(subscriber).unwrap();
 /// ```
-#[derive(Copy, Clone, Debug, Default)]
+///
+/// # Multiple layers
+///
+/// It is possible to have multiple bridges in the same subscriber, for example to feed different
+/// [`dipstick`] outputs. Each one created through [`new`][DipstickLayer::new] keeps its own state
+/// for the spans, with its own root scope. Clones of a layer, on the other hand, are considered to
+/// be the same bridge and are not meant to be placed into the same subscriber together.
+#[derive(Clone, Debug)]
 pub struct DipstickLayer<S> {
-    scope: S,
+    id: usize,
+    root: Arc<ScopeNode<S>>,
+    plans: Arc<Plans>,
+    level_leaks: Option<Detector>,
+}
+
+impl<S> Default for DipstickLayer<S>
+where
+    S: Sink + Default,
+{
+    fn default() -> Self {
+        Self::new(S::default())
+    }
 }
 
 impl<S> DipstickLayer<S>
 where
-    S: Clone + InputScope + Prefixed + 'static,
+    S: Sink,
 {
     /// Creates the bridge.
     ///
-    /// Expects the scope into which it will put metrics.
+    /// Expects the scope into which it will put metrics. That is usually a [`dipstick`] scope, but
+    /// can be any other [`Sink`].
     pub fn new(input_scope: S) -> Self {
-        DipstickLayer { scope: input_scope }
+        DipstickLayer {
+            id: NEXT_LAYER_ID.fetch_add(1, Ordering::Relaxed),
+            root: Arc::new(ScopeNode::new(input_scope, String::new(), None)),
+            plans: Arc::default(),
+            level_leaks: None,
+        }
+    }
+
+    /// Publishes the highest value each level reached since the previous flush.
+    ///
+    /// The value of a level is sampled only when the flush happens. Short spikes in between are
+    /// lost, which is a problem for example when sizing connection pools. With this turned on, a
+    /// gauge named like the level with `.max` appended (eg. `active.max`) is sent with every flush
+    /// of the scope.
+    ///
+    /// The extremes are of the whole level, across all the labels.
+    ///
+    /// This covers the levels managed by this layer only. The highest value is measured from when
+    /// the level was used the first time through the layer (starting at 0). The sink needs to
+    /// [support observing][Sink::observe_on_flush] (the [`dipstick`] scopes do).
+    pub fn level_max(self) -> Self {
+        let min = self.root.extremes.as_ref().is_some_and(|e| e.min);
+        self.extremes(true, min)
+    }
+
+    /// Publishes the lowest value each level reached since the previous flush.
+    ///
+    /// The counterpart of [`level_max`][DipstickLayer::level_max], with gauges suffixed with
+    /// `.min`.
+    pub fn level_min(self) -> Self {
+        let max = self.root.extremes.as_ref().is_some_and(|e| e.max);
+        self.extremes(max, true)
+    }
+
+    fn extremes(self, max: bool, min: bool) -> Self {
+        let extremes = Extremes::new(max, min);
+        let root = ScopeNode::new(
+            self.root.scope.clone(),
+            String::new(),
+            Some(Arc::new(extremes)),
+        );
+        DipstickLayer {
+            root: Arc::new(root),
+            ..self
+        }
+    }
+
+    /// Sets the detector of levels left incremented.
+    ///
+    /// When a span closes with some of its `metrics.level.inc` not paired with a
+    /// `metrics.level.dec`, the detector is called. These usually indicate a bug in the
+    /// instrumentation (a forgotten or misspelled decrement), which causes the level to drift.
+    ///
+    /// The detection is opt-in, in debug builds too. The library has no good place to report to on
+    /// its own: printing would mix into the output of the application and logging through
+    /// `tracing` would feed back into the subscriber that is closing the span. The application
+    /// decides where these go (a log, a counter, a panic in tests), possibly only under
+    /// `cfg!(debug_assertions)`. `None` turns the detection off again.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    ///
+    /// use dipstick::AtomicBucket;
+    /// use tracing::subscriber;
+    /// use tracing_dipstick::{DipstickLayer, LevelLeak};
+    /// use tracing_subscriber::layer::SubscriberExt;
+    /// use tracing_subscriber::Registry;
+    ///
+    /// let bridge = DipstickLayer::new(AtomicBucket::new()).level_leaks(Some(Arc::new(
+    ///     |leak: &LevelLeak| eprintln!("Instrumentation bug: {}", leak),
+    /// )));
+    /// subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+    /// ```
+    pub fn level_leaks(self, detector: Option<LeakDetector>) -> Self {
+        DipstickLayer {
+            level_leaks: detector.map(Detector),
+            ..self
+        }
+    }
+}
+
+impl<S: Send + Sync + 'static> DipstickLayer<S> {
+    fn get_scope<'e>(&self, extensions: &'e Extensions) -> Option<&'e Scope<S>> {
+        extensions
+            .get::<Scopes<S>>()?
+            .0
+            .iter()
+            .find(|(id, _)| *id == self.id)
+            .map(|(_, scope)| scope)
+    }
+
+    fn get_scope_mut<'e>(&self, extensions: &'e mut ExtensionsMut) -> Option<&'e mut Scope<S>> {
+        extensions
+            .get_mut::<Scopes<S>>()?
+            .0
+            .iter_mut()
+            .find(|(id, _)| *id == self.id)
+            .map(|(_, scope)| scope)
+    }
+
+    fn insert_scope(&self, extensions: &mut ExtensionsMut, scope: Scope<S>) {
+        match extensions.get_mut::<Scopes<S>>() {
+            Some(scopes) => match scopes.0.iter_mut().find(|(id, _)| *id == self.id) {
+                Some(old) => old.1 = scope,
+                None => scopes.0.push((self.id, scope)),
+            },
+            None => extensions.insert(Scopes(vec![(self.id, scope)])),
+        }
+    }
+
+    fn remove_scope(&self, extensions: &mut ExtensionsMut) -> Option<Scope<S>> {
+        let scopes = extensions.get_mut::<Scopes<S>>()?;
+        let pos = scopes.0.iter().position(|(id, _)| *id == self.id)?;
+        let (_, scope) = scopes.0.swap_remove(pos);
+        if scopes.0.is_empty() {
+            extensions.remove::<Scopes<S>>();
+        }
+        Some(scope)
+    }
+
+    /// Pairs a `metrics.level.inc` or `metrics.level.dec` of an event with the owning span.
+    fn pair<'a, I>(&self, span: Option<SpanRef<'a, I>>, path: String, level: &str, delta: i64)
+    where
+        I: LookupSpan<'a>,
+    {
+        for span in span.iter().flat_map(SpanRef::scope) {
+            let mut extensions = span.extensions_mut();
+            if let Some(scope) = self.get_scope_mut(&mut extensions) {
+                if delta > 0 {
+                    scope.add_paired(path, level);
+                    return;
+                } else if scope.settle_paired(&path, level) {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Provides the scope node and labels of the span, or of its nearest ancestor that has one.
+    ///
+    /// Only the spans touching metrics hold any state, the others are skipped. Without any such
+    /// span, the root is used. The node is only borrowed, under the lock of the span's extensions.
+    fn with_nearest<'a, I, R>(
+        &self,
+        span: Option<SpanRef<'a, I>>,
+        f: impl FnOnce(&Arc<ScopeNode<S>>, &Labels) -> R,
+    ) -> R
+    where
+        I: LookupSpan<'a>,
+    {
+        for span in span.iter().flat_map(SpanRef::scope) {
+            let extensions = span.extensions();
+            if let Some(scope) = self.get_scope(&extensions) {
+                return f(&scope.node, &scope.labels);
+            }
+        }
+        f(&self.root, &Labels::default())
+    }
+
+    fn nearest<'a, I>(&self, span: Option<SpanRef<'a, I>>) -> (Arc<ScopeNode<S>>, Labels)
+    where
+        I: LookupSpan<'a>,
+    {
+        self.with_nearest(span, |node, labels| (Arc::clone(node), labels.clone()))
     }
 }
 
 impl<S, I> Layer<I> for DipstickLayer<S>
 where
-    S: Clone + InputScope + Prefixed + Send + Sync + 'static,
+    S: Sink,
     I: Subscriber,
     for<'l> I: LookupSpan<'l>,
 {
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        // The metrics themselves are created on first use, only then it is known in which scope.
+        self.plans.register(metadata);
+        Interest::always()
+    }
     fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<I>) {
-        let named = |scope: &S| -> S {
-            let mut named: Option<S> = None;
-            struct NameVisitor<'a, S> {
-                target: &'a mut Option<S>,
-                src: &'a S,
+        let span = ctx.span(id).expect("Missing newly created span");
+        self.plans.with(attrs.metadata(), |plan| {
+            // Spans without anything related to metrics are not interesting, they are simply
+            // skipped when looking for the scope.
+            if !plan.is_scoped() && !plan.has_metrics() && !plan.has_labels() {
+                return;
             }
-            impl<S> Visit for NameVisitor<'_, S>
-            where
-                S: Prefixed,
-            {
-                fn record_debug(&mut self, _: &Field, _: &dyn Debug) {}
-                fn record_str(&mut self, field: &Field, value: &str) {
-                    let name = field.name();
-                    if name == SCOPE_NAME {
-                        *self.target = Some(self.src.add_name(value));
-                    } else if name == SCOPE_NAME_FULL {
-                        *self.target = Some(self.src.named(value));
+            // The registry has already resolved the parent ‒ an explicit one, the contextual one
+            // or none at all for root spans.
+            let (parent, labels) = self.nearest(span.parent());
+            let node = if plan.is_scoped() {
+                derived(&parent, plan, |visitor| attrs.record(visitor)).unwrap_or(parent)
+            } else {
+                parent
+            };
+            let labels = if plan.has_labels() {
+                labeled(&labels, plan, |visitor| attrs.record(visitor))
+            } else {
+                labels
+            };
+
+            let mut scope = PointWrap {
+                point: Scope::new(node, labels),
+                plan,
+            };
+            attrs.record(&mut scope);
+
+            self.insert_scope(&mut span.extensions_mut(), scope.point);
+        });
+    }
+    fn on_record(&self, id: &Id, values: &Record, ctx: Context<I>) {
+        let span = ctx.span(id).expect("Missing recorded span");
+        self.plans.with(span.metadata(), |plan| {
+            // A late scope is derived from the parent, the same way as when the span is created.
+            let renamed = if plan.is_scoped() {
+                self.with_nearest(span.parent(), |parent, _| {
+                    derived(parent, plan, |visitor| values.record(visitor))
+                })
+            } else {
+                None
+            };
+
+            let mut extensions = span.extensions_mut();
+            if let Some(scope) = self.get_scope_mut(&mut extensions) {
+                if let Some(renamed) = renamed {
+                    scope.node = renamed;
+                }
+                values.record(&mut PointWrap { point: scope, plan });
+            }
+        });
+    }
+    fn on_enter(&self, id: &Id, ctx: Context<I>) {
+        if !self.plans.any_entering() {
+            return;
+        }
+        let span = ctx.span(id).expect("Missing entered span");
+        if !self.plans.with(span.metadata(), Plan::tracks_entering) {
+            return;
+        }
+        let mut extensions = span.extensions_mut();
+        if let Some(scope) = self.get_scope_mut(&mut extensions) {
+            scope.enter();
+        }
+    }
+    fn on_exit(&self, id: &Id, ctx: Context<I>) {
+        if !self.plans.any_entering() {
+            return;
+        }
+        let span = ctx.span(id).expect("Missing exited span");
+        if !self.plans.with(span.metadata(), Plan::tracks_entering) {
+            return;
+        }
+        let mut extensions = span.extensions_mut();
+        if let Some(scope) = self.get_scope_mut(&mut extensions) {
+            scope.exit();
+        }
+    }
+    fn on_close(&self, id: Id, ctx: Context<I>) {
+        let span = ctx.span(&id).expect("Missing closed span");
+        // The state is finalized (by dropping it) right now. The storage of the span might get
+        // cleared only later on.
+        let Some(scope) = self.remove_scope(&mut span.extensions_mut()) else {
+            return;
+        };
+        if let Some(Detector(detector)) = &self.level_leaks {
+            for (path, level, unpaired) in &scope.paired {
+                detector(&LevelLeak {
+                    span: span.name(),
+                    scope: path,
+                    level,
+                    unpaired: *unpaired,
+                });
+            }
+        }
+        if self.plans.any_self_timed() && scope.is_timed() {
+            // Attribute the time to the nearest timed ancestor, for its self timers.
+            let lifetime = scope.busy.lifetime();
+            for parent in span.scope().skip(1) {
+                let mut extensions = parent.extensions_mut();
+                if let Some(parent) = self.get_scope_mut(&mut extensions) {
+                    if parent.is_timed() {
+                        parent.nested += lifetime;
+                        break;
                     }
                 }
             }
-            attrs.record(&mut NameVisitor {
-                target: &mut named,
-                src: scope,
-            });
-            named.unwrap_or_else(|| scope.clone())
+        }
+    }
+    fn on_id_change(&self, old: &Id, new: &Id, ctx: Context<I>) {
+        // Some subscribers hand out a new id when cloning a span. The state moves to the newest one,
+        // so it is still finalized exactly once.
+        let (Some(old), Some(new)) = (ctx.span(old), ctx.span(new)) else {
+            return;
         };
-        let scope = ctx
-            .lookup_current()
-            .and_then(|current| {
-                current
-                    .extensions()
-                    .get::<Scope<S>>()
-                    .map(|Scope { scope: s, .. }| named(s))
-            })
-            .unwrap_or_else(|| named(&self.scope));
-
-        let mut scope = PointWrap(Scope {
-            scope,
-            timers: Vec::new(),
-            levels: Vec::new(),
-        });
-        attrs.record(&mut scope);
-
-        ctx.span(id)
-            .expect("Missing newly created span")
-            .extensions_mut()
-            .insert(scope.0);
+        let scope = self.remove_scope(&mut old.extensions_mut());
+        if let Some(scope) = scope {
+            self.insert_scope(&mut new.extensions_mut(), scope);
+        }
     }
-    // TODO: How about cloning/creating new IDs for spans?
     fn on_event(&self, event: &Event, ctx: Context<I>) {
-        // TODO: Currently, we store a scope in each span. Instead we should store it only in the
-        // ones that are interesting. In particular:
-        // * Score on creation only if the span itself touches metrics (either has some or has a
-        //   metric scope).
-        // * Initialize it lazily on the first access. But extensions_mut might be slower?
-        let scope = Lazy::new(|| {
-            ctx.lookup_current()
-                .map(|c| {
-                    // FIXME: It would be nice to avoid the clone. That should be possible, in
-                    // theory.
-                    c.extensions()
-                        .get::<Scope<S>>()
-                        .expect("Missing prepared scope")
-                        .scope
-                        .clone()
-                })
-                .unwrap_or_else(|| self.scope.clone())
+        self.plans.with(event.metadata(), |plan| {
+            if !plan.has_metrics() {
+                return;
+            }
+            // Takes the explicit parent of the event into account, if there's one.
+            let paired = self.with_nearest(ctx.event_span(event), |node, labels| {
+                let mut wrap = PointWrap {
+                    point: EventPoint {
+                        node,
+                        labels,
+                        paired: Vec::new(),
+                    },
+                    plan,
+                };
+                event.record(&mut wrap);
+                wrap.point.paired
+            });
+            for (path, level, delta) in paired {
+                self.pair(ctx.event_span(event), path, &level, delta);
+            }
         });
-
-        event.record(&mut PointWrap(scope));
     }
 }
@@ -0,0 +1,151 @@
+//! A [`Sink`] feeding OpenTelemetry instruments.
+
+use std::borrow::Cow;
+
+use opentelemetry::metrics::Meter;
+use opentelemetry::{Key, KeyValue};
+
+use crate::sink::{Label, Metric, MetricKind, ScopePath, Sink};
+
+/// Adds the labels to the attributes of the scope.
+fn attributes<'a>(scope: &'a [KeyValue], labels: &[Label]) -> Cow<'a, [KeyValue]> {
+    if labels.is_empty() {
+        return Cow::Borrowed(scope);
+    }
+    let labels = labels
+        .iter()
+        .map(|label| KeyValue::new(label.key(), label.value().to_owned()));
+    Cow::Owned(scope.iter().cloned().chain(labels).collect())
+}
+
+/// A [`Sink`] creating the metrics as [OpenTelemetry](https://docs.rs/opentelemetry) instruments.
+///
+/// The instruments are created through the given [`Meter`]:
+///
+/// * Counters and markers become [`Counter`][opentelemetry::metrics::Counter]s. As these are
+///   monotonic, negative values are ignored.
+/// * Levels become [`UpDownCounter`][opentelemetry::metrics::UpDownCounter]s.
+/// * Gauges become [`Gauge`][opentelemetry::metrics::Gauge]s of floating point numbers.
+/// * Timers become [`Histogram`][opentelemetry::metrics::Histogram]s, recording the time in
+///   seconds (with the `s` unit).
+///
+/// The counters and levels hold integers, floating point values written into them are rounded.
+///
+/// The labels from the `metrics.label.*` attributes become attributes of the recorded values.
+///
+/// Note that OpenTelemetry restricts the characters in instrument names. Metrics with other names
+/// are not collected (the SDK reports them through its own diagnostics).
+///
+/// The instruments are exported by the readers of the meter provider, so
+/// [`observe_on_flush`][Sink::observe_on_flush] does nothing.
+///
+/// Available with the `opentelemetry` feature.
+///
+/// # Scopes
+///
+/// The instrument names carry the full names of their scopes (see [`Sink::scope`]). With
+/// [`scope_attribute`][OtelMeter::scope_attribute], the scopes are recorded as an attribute
+/// instead.
+///
+/// # Examples
+///
+/// ```rust
+/// use opentelemetry::metrics::MeterProvider;
+/// use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+/// use tracing::{debug, subscriber};
+/// use tracing_dipstick::{DipstickLayer, OtelMeter};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let exporter = InMemoryMetricExporter::default();
+/// let provider = SdkMeterProvider::builder()
+///     .with_reader(PeriodicReader::builder(exporter.clone()).build())
+///     .build();
+///
+/// let bridge = DipstickLayer::new(OtelMeter::new(provider.meter("yaks")));
+/// let subscriber = Registry::default().with(bridge);
+/// subscriber::with_default(subscriber, || debug!(metrics.counter = "shaved"));
+///
+/// provider.force_flush().unwrap();
+/// assert!(!exporter.get_finished_metrics().unwrap().is_empty());
+/// ```
+#[derive(Clone, Debug)]
+pub struct OtelMeter {
+    meter: Meter,
+    path: ScopePath<Key>,
+}
+
+impl OtelMeter {
+    /// Creates the sink, with scopes prefixed to the instrument names.
+    pub fn new(meter: Meter) -> Self {
+        OtelMeter {
+            meter,
+            path: ScopePath::default(),
+        }
+    }
+
+    /// Records the scopes as an attribute of the given key, instead of in the instrument names.
+    ///
+    /// The instruments in the root scope have no such attribute.
+    pub fn scope_attribute<K: Into<Key>>(self, key: K) -> Self {
+        OtelMeter {
+            path: self.path.with_key(key.into()),
+            ..self
+        }
+    }
+
+    fn key(&self, name: &str) -> (String, Vec<KeyValue>) {
+        let (name, attribute) = self.path.key(name);
+        let attributes = attribute.map(|(key, path)| KeyValue::new(key, path));
+        (name, attributes.into_iter().collect())
+    }
+}
+
+impl Sink for OtelMeter {
+    fn new_metric(&self, name: &str, kind: MetricKind) -> Metric {
+        let (name, scope) = self.key(name);
+        match kind {
+            MetricKind::Counter | MetricKind::Marker => {
+                let counter = self.meter.u64_counter(name).build();
+                Metric::new(move |value, labels| {
+                    if let Ok(value) = u64::try_from(value.as_i64()) {
+                        counter.add(value, &attributes(&scope, labels));
+                    }
+                })
+            }
+            MetricKind::Gauge => {
+                let gauge = self.meter.f64_gauge(name).build();
+                Metric::new(move |value, labels| {
+                    gauge.record(value.as_f64(), &attributes(&scope, labels));
+                })
+            }
+            MetricKind::Level => {
+                let level = self.meter.i64_up_down_counter(name).build();
+                Metric::new(move |value, labels| {
+                    level.add(value.as_i64(), &attributes(&scope, labels));
+                })
+            }
+            MetricKind::Timer => {
+                let histogram = self.meter.f64_histogram(name).with_unit("s").build();
+                Metric::new(move |micros, labels| {
+                    let seconds = micros.as_f64() / 1_000_000.0;
+                    histogram.record(seconds, &attributes(&scope, labels));
+                })
+            }
+        }
+    }
+
+    fn scope(&self, name: &str) -> Self {
+        OtelMeter {
+            meter: self.meter.clone(),
+            path: self.path.scope(name),
+        }
+    }
+
+    fn scope_full(&self, name: &str) -> Self {
+        OtelMeter {
+            meter: self.meter.clone(),
+            path: self.path.scope_full(name),
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! Precompiled handling of callsites.
+//!
+//! The fields of each callsite are analyzed only once, when it is registered. Recording the values
+//! then only looks up what to do with the field by its index, no string comparisons are involved.
+
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+use tracing_core::callsite::Identifier;
+use tracing_core::field::Field;
+use tracing_core::Metadata;
+
+use crate::{read, write, MetricType, LABEL_PREFIX, METRIC_TYPES, SCOPE_NAME, SCOPE_NAME_FULL};
+
+/// What to do with a field of a callsite.
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum FieldPlan {
+    /// The `metrics.scope` (or `metrics.scope.full`, if `true`).
+    Scope(bool),
+    /// A metric of the given type, named by the value of the field.
+    Named(MetricType),
+    /// The value for the metric of the given type and name.
+    Valued(MetricType, &'static str),
+    /// The value of the label with the given key.
+    Label(&'static str),
+}
+
+/// The analyzed fields of a callsite.
+#[derive(Debug)]
+pub(crate) struct Plan {
+    /// Indexed by [`Field::index`].
+    fields: Vec<Option<FieldPlan>>,
+    scoped: bool,
+    metrics: bool,
+    labeled: bool,
+    entering: bool,
+    self_timed: bool,
+}
+
+impl Plan {
+    pub(crate) fn new(metadata: &Metadata) -> Self {
+        let span = metadata.is_span();
+        let fields: Vec<_> = metadata
+            .fields()
+            .iter()
+            .map(|field| Self::field(field.name(), span))
+            .collect();
+        let scoped = fields
+            .iter()
+            .any(|field| matches!(field, Some(FieldPlan::Scope(_))));
+        let metrics = fields
+            .iter()
+            .any(|field| matches!(field, Some(FieldPlan::Named(_) | FieldPlan::Valued(..))));
+        let labeled = fields
+            .iter()
+            .any(|field| matches!(field, Some(FieldPlan::Label(_))));
+        let entering = fields
+            .iter()
+            .any(|field| matches!(field, Some(FieldPlan::Named(tp)) if tp.is_tracked()));
+        let self_timed = fields
+            .iter()
+            .any(|field| matches!(field, Some(FieldPlan::Named(MetricType::SelfTimer))));
+        Plan {
+            fields,
+            scoped,
+            metrics,
+            labeled,
+            entering,
+            self_timed,
+        }
+    }
+
+    fn field(name: &'static str, span: bool) -> Option<FieldPlan> {
+        if span && name == SCOPE_NAME {
+            return Some(FieldPlan::Scope(false));
+        }
+        if span && name == SCOPE_NAME_FULL {
+            return Some(FieldPlan::Scope(true));
+        }
+        if span && name.starts_with(LABEL_PREFIX) {
+            return Some(FieldPlan::Label(&name[LABEL_PREFIX.len()..]));
+        }
+        METRIC_TYPES.iter().find_map(|tp| {
+            if (tp.3 || span) && name == tp.0 {
+                Some(FieldPlan::Named(tp.2))
+            } else if tp.3 && !tp.1.is_empty() && name.starts_with(tp.1) {
+                Some(FieldPlan::Valued(tp.2, &name[tp.1.len()..]))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Finds out what to do with the field.
+    pub(crate) fn get(&self, field: &Field) -> Option<FieldPlan> {
+        self.fields.get(field.index()).copied().flatten()
+    }
+
+    /// Does the callsite define a scope?
+    pub(crate) fn is_scoped(&self) -> bool {
+        self.scoped
+    }
+
+    /// Does the callsite have any metrics?
+    pub(crate) fn has_metrics(&self) -> bool {
+        self.metrics
+    }
+
+    /// Does the callsite define any labels?
+    pub(crate) fn has_labels(&self) -> bool {
+        self.labeled
+    }
+
+    /// Do the metrics of the callsite need to know when the span is entered and exited?
+    pub(crate) fn tracks_entering(&self) -> bool {
+        self.entering
+    }
+
+    /// Does the callsite have a self timer?
+    pub(crate) fn is_self_timed(&self) -> bool {
+        self.self_timed
+    }
+}
+
+/// A hasher for the callsite identifiers.
+///
+/// These are just addresses of statics, there's no need for anything DoS-resistant and the lookup
+/// is on the path of every single event.
+#[derive(Debug, Default)]
+pub(crate) struct CallsiteHasher(u64);
+
+impl Hasher for CallsiteHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.write_u8(*byte);
+        }
+    }
+
+    fn write_u8(&mut self, i: u8) {
+        self.write_u64(i.into());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.0 = (self.0.rotate_left(5) ^ i).wrapping_mul(0x51_7c_c1_b7_27_22_0a_95);
+    }
+}
+
+/// A map keyed by callsites.
+pub(crate) type CallsiteMap<V> = HashMap<Identifier, V, BuildHasherDefault<CallsiteHasher>>;
+
+/// The plans of all the callsites seen so far.
+#[derive(Debug, Default)]
+pub(crate) struct Plans {
+    plans: RwLock<CallsiteMap<Plan>>,
+    /// Set once any of the plans [tracks entering][Plan::tracks_entering].
+    entering: AtomicBool,
+    /// Set once any of the plans [is self timed][Plan::is_self_timed].
+    self_timed: AtomicBool,
+}
+
+impl Plans {
+    fn analyze(&self, metadata: &Metadata) -> Plan {
+        let plan = Plan::new(metadata);
+        if plan.tracks_entering() {
+            self.entering.store(true, Ordering::Relaxed);
+        }
+        if plan.is_self_timed() {
+            self.self_timed.store(true, Ordering::Relaxed);
+        }
+        plan
+    }
+
+    /// Does any of the callsites have a self timer?
+    ///
+    /// Without them, closing the spans doesn't have to be tracked.
+    pub(crate) fn any_self_timed(&self) -> bool {
+        self.self_timed.load(Ordering::Relaxed)
+    }
+
+    /// Analyzes the callsite (again, if it was already registered).
+    pub(crate) fn register(&self, metadata: &'static Metadata<'static>) {
+        let plan = self.analyze(metadata);
+        write(&self.plans).insert(metadata.callsite(), plan);
+    }
+
+    /// Does any of the callsites need to know about entering and exiting spans?
+    ///
+    /// Allows skipping the lookups of the spans when they are entered if not.
+    pub(crate) fn any_entering(&self) -> bool {
+        self.entering.load(Ordering::Relaxed)
+    }
+
+    /// Provides the plan of a callsite.
+    ///
+    /// Usually, the callsite is already registered, but it is analyzed on the spot if not. That is
+    /// also why the plans are behind a lock, but in the common case it is taken only for reading
+    /// and the lookup hashes just the address of the callsite.
+    pub(crate) fn with<R>(
+        &self,
+        metadata: &'static Metadata<'static>,
+        f: impl FnOnce(&Plan) -> R,
+    ) -> R {
+        let callsite = metadata.callsite();
+        if let Some(plan) = read(&self.plans).get(&callsite) {
+            return f(plan);
+        }
+        // Not calling f under the write lock, it could block all the other threads for long.
+        write(&self.plans)
+            .entry(callsite.clone())
+            .or_insert_with(|| self.analyze(metadata));
+        f(read(&self.plans)
+            .get(&callsite)
+            .expect("Missing freshly analyzed plan"))
+    }
+}
@@ -0,0 +1,377 @@
+//! An in-process registry of the metrics, scraped by Prometheus.
+
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult, Write as _};
+use std::io::{BufRead, BufReader, Error as IoError, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use crate::sink::{self, Label, Metric, MetricKind, MetricValue, Sink};
+
+/// How long a scraping connection may stay silent before it is dropped.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The current value of a metric.
+enum Value {
+    /// Counters, gauges and levels, the bits of a `f64`.
+    Number(AtomicU64),
+    /// Timers, the sum (in microseconds) and count.
+    Summary(AtomicI64, AtomicU64),
+    /// Gauges computed on each scrape.
+    Observed(Box<dyn Fn() -> isize + Send + Sync>),
+}
+
+impl Value {
+    fn new(kind: MetricKind) -> Self {
+        match kind {
+            MetricKind::Timer => Value::Summary(AtomicI64::new(0), AtomicU64::new(0)),
+            _ => Value::Number(AtomicU64::new(0.0f64.to_bits())),
+        }
+    }
+
+    fn write(&self, kind: MetricKind, v: MetricValue) {
+        // Counters can't go down (and NaN would break them for good).
+        let decreasing = v.as_f64() < 0.0 || v.as_f64().is_nan();
+        match (kind, self) {
+            (MetricKind::Counter | MetricKind::Marker, _) if decreasing => (),
+            (MetricKind::Gauge, Value::Number(value)) => {
+                value.store(v.as_f64().to_bits(), Ordering::Relaxed)
+            }
+            (_, Value::Number(value)) => {
+                let add = |old: u64| Some((f64::from_bits(old) + v.as_f64()).to_bits());
+                // Always succeeds, the closure always returns Some.
+                let _ = value.fetch_update(Ordering::Relaxed, Ordering::Relaxed, add);
+            }
+            (_, Value::Summary(sum, count)) => {
+                sum.fetch_add(v.as_i64(), Ordering::Relaxed);
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+            (_, Value::Observed(_)) => unreachable!("Observed gauges are not written into"),
+        }
+    }
+
+    /// Writes the sample lines, the labels are already rendered (including the braces).
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        // Writing into a String can't fail.
+        let _ = match self {
+            Value::Number(value) => {
+                let value = number(f64::from_bits(value.load(Ordering::Relaxed)));
+                writeln!(out, "{}{} {}", name, labels, value)
+            }
+            Value::Summary(sum, count) => {
+                let sum = sum.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+                let count = count.load(Ordering::Relaxed);
+                writeln!(out, "{}_sum{} {}", name, labels, sum)
+                    .and_then(|()| writeln!(out, "{}_count{} {}", name, labels, count))
+            }
+            Value::Observed(observe) => writeln!(out, "{}{} {}", name, labels, observe()),
+        };
+    }
+}
+
+/// Formats the number the way Prometheus expects it.
+fn number(value: f64) -> String {
+    if value.is_infinite() {
+        let sign = if value > 0.0 { "+" } else { "-" };
+        format!("{}Inf", sign)
+    } else {
+        // Including NaN, which is rendered as such.
+        value.to_string()
+    }
+}
+
+/// The values of a metric for each set of labels (sorted, unique by keys).
+type Series = Mutex<BTreeMap<Vec<Label>, Arc<Value>>>;
+
+struct Entry {
+    kind: MetricKind,
+    /// The name as it came from the layer, before sanitizing.
+    original: String,
+    /// The value without any labels, created when first written into.
+    value: Arc<OnceLock<Value>>,
+    series: Arc<Series>,
+}
+
+/// Renders the labels as `{key="value",...}`.
+fn render_labels(labels: &[Label]) -> String {
+    let labels = labels
+        .iter()
+        .map(|label| {
+            let value = label
+                .value()
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{}=\"{}\"", sanitize(label.key()), value)
+        })
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Turns the name into something Prometheus accepts.
+///
+/// The dots from the scopes (and anything else not allowed) become underscores.
+fn sanitize(name: &str) -> String {
+    let mut sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !sanitized.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':') {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+/// A [`Sink`] keeping the metrics in memory and serving them to Prometheus.
+///
+/// Unlike the push-oriented outputs, this one is scraped. It holds the current value of every
+/// metric the [`DipstickLayer`][crate::DipstickLayer] records and renders them in the Prometheus
+/// text exposition format, either [directly][PrometheusRegistry::render] or through a small
+/// built-in [HTTP endpoint][PrometheusRegistry::serve].
+///
+/// The metrics are exposed like this:
+///
+/// * Counters and markers as `counter`s, with the `_total` suffix. Negative values are ignored.
+/// * Gauges as `gauge`s.
+/// * Levels as `gauge`s, holding the sum of all the increments and decrements.
+/// * Timers as `summary`s (the sum and count, without quantiles) in seconds, with the `_seconds`
+///   suffix.
+///
+/// The names are sanitized, the dots separating the scopes and any other characters not allowed
+/// by Prometheus become underscores (eg. `shaving.yak.time` becomes `shaving_yak_time_seconds`).
+/// If two metrics end up with the same name, they are merged if they are of the same kind,
+/// otherwise the later one is not exposed. The gauges observed on flush (eg. the extremes of
+/// levels) are never merged. The original name is kept in the `HELP` line.
+///
+/// The labels from the `metrics.label.*` attributes are rendered as Prometheus labels, each
+/// combination of them being a separate series. Their keys are sanitized the same way as the names.
+///
+/// Each scrape counts as a flush for the gauges [observed on flush][Sink::observe_on_flush] (eg.
+/// the [extremes of levels][crate::DipstickLayer::level_max]).
+///
+/// Available with the `prometheus` feature.
+///
+/// # Examples
+///
+/// ```rust
+/// use tracing::{debug, subscriber};
+/// use tracing_dipstick::{DipstickLayer, PrometheusRegistry};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let registry = PrometheusRegistry::new();
+/// let addr = registry.serve("127.0.0.1:0").unwrap();
+/// println!("Scrape http://{}/metrics", addr);
+///
+/// let subscriber = Registry::default().with(DipstickLayer::new(registry.clone()));
+/// subscriber::with_default(subscriber, || debug!(metrics.counter = "requests"));
+///
+/// assert!(registry.render().contains("\nrequests_total 1\n"));
+/// ```
+#[derive(Clone, Default)]
+pub struct PrometheusRegistry {
+    /// By the sanitized names, shared by all the scopes.
+    metrics: Arc<Mutex<BTreeMap<String, Entry>>>,
+    /// The full name of the scope, dot-separated.
+    path: String,
+}
+
+impl PrometheusRegistry {
+    /// Creates an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn register(
+        &self,
+        original: String,
+        kind: MetricKind,
+    ) -> Option<(Arc<OnceLock<Value>>, Arc<Series>)> {
+        let mut name = sanitize(&original);
+        match kind {
+            MetricKind::Counter | MetricKind::Marker if !name.ends_with("_total") => {
+                name.push_str("_total");
+            }
+            MetricKind::Timer => name.push_str("_seconds"),
+            _ => (),
+        }
+        let mut metrics = self.metrics.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = metrics.entry(name).or_insert_with(|| Entry {
+            kind,
+            original,
+            value: Arc::default(),
+            series: Arc::default(),
+        });
+        let same = match (entry.kind, kind) {
+            (
+                MetricKind::Counter | MetricKind::Marker,
+                MetricKind::Counter | MetricKind::Marker,
+            ) => true,
+            (old, new) => old == new,
+        };
+        if !same {
+            return None;
+        }
+        if let Some(Value::Observed(_)) = entry.value.get() {
+            // Taken by a gauge observed on flush.
+            return None;
+        }
+        Some((Arc::clone(&entry.value), Arc::clone(&entry.series)))
+    }
+
+    /// Renders all the metrics in the Prometheus text exposition format (version 0.0.4).
+    pub fn render(&self) -> String {
+        let metrics = self.metrics.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut out = String::new();
+        for (name, entry) in metrics.iter() {
+            let series = entry.series.lock().unwrap_or_else(PoisonError::into_inner);
+            // Only the series actually written into are exposed.
+            if entry.value.get().is_none() && series.is_empty() {
+                continue;
+            }
+            let (tp, help) = match entry.kind {
+                MetricKind::Counter => ("counter", "Counter"),
+                MetricKind::Marker => ("counter", "Marker"),
+                MetricKind::Gauge => ("gauge", "Gauge"),
+                MetricKind::Level => ("gauge", "Level"),
+                MetricKind::Timer => ("summary", "Timer"),
+            };
+            let original = entry.original.replace('\\', "\\\\").replace('\n', "\\n");
+            // Writing into a String can't fail.
+            let _ = writeln!(out, "# HELP {} {} {}", name, help, original);
+            let _ = writeln!(out, "# TYPE {} {}", name, tp);
+            if let Some(value) = entry.value.get() {
+                value.render(&mut out, name, "");
+            }
+            for (labels, value) in series.iter() {
+                value.render(&mut out, name, &render_labels(labels));
+            }
+        }
+        out
+    }
+
+    /// Starts serving the metrics over HTTP on the given address.
+    ///
+    /// The returned address is the one actually bound (useful with port `0`). The metrics are
+    /// served on the `/metrics` path, from a background thread, one request at a time. It runs
+    /// for the rest of the life of the program.
+    ///
+    /// This is a minimal HTTP server meant for scraping on a local or otherwise trusted network,
+    /// there's no encryption or authentication.
+    pub fn serve<A: ToSocketAddrs>(&self, addr: A) -> Result<SocketAddr, IoError> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let registry = self.clone();
+        thread::Builder::new()
+            .name("tracing-dipstick-prometheus".to_owned())
+            .spawn(move || {
+                for conn in listener.incoming() {
+                    // Problems with a single connection are the problems of the scraper.
+                    let _ = conn.and_then(|conn| registry.respond(conn));
+                }
+            })?;
+        Ok(addr)
+    }
+
+    fn respond(&self, conn: TcpStream) -> Result<(), IoError> {
+        conn.set_read_timeout(Some(TIMEOUT))?;
+        conn.set_write_timeout(Some(TIMEOUT))?;
+        let mut reader = BufReader::new(&conn);
+        let mut request = String::new();
+        reader.read_line(&mut request)?;
+        // Skip the headers, we don't need any of them.
+        let mut header = String::new();
+        while reader.read_line(&mut header)? > 2 {
+            header.clear();
+        }
+
+        let mut parts = request.split_whitespace();
+        let (status, body) = match (parts.next(), parts.next()) {
+            (Some("GET"), Some("/metrics")) => ("200 OK", self.render()),
+            (Some("GET"), _) => ("404 Not Found", String::new()),
+            _ => ("405 Method Not Allowed", String::new()),
+        };
+        write!(
+            &conn,
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        )
+    }
+}
+
+impl Debug for PrometheusRegistry {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.debug_struct("PrometheusRegistry")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Sink for PrometheusRegistry {
+    fn new_metric(&self, name: &str, kind: MetricKind) -> Metric {
+        let Some((value, series)) = self.register(sink::join(&self.path, name), kind) else {
+            return Metric::new(|_, _| ());
+        };
+        Metric::new(move |v, labels| {
+            if labels.is_empty() {
+                return value.get_or_init(|| Value::new(kind)).write(kind, v);
+            }
+            let mut labels = labels.to_vec();
+            labels.sort();
+            let value = Arc::clone(
+                series
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .entry(labels)
+                    .or_insert_with(|| Arc::new(Value::new(kind))),
+            );
+            value.write(kind, v);
+        })
+    }
+
+    fn scope(&self, name: &str) -> Self {
+        PrometheusRegistry {
+            metrics: Arc::clone(&self.metrics),
+            path: sink::join(&self.path, name),
+        }
+    }
+
+    fn scope_full(&self, name: &str) -> Self {
+        PrometheusRegistry {
+            metrics: Arc::clone(&self.metrics),
+            path: name.to_owned(),
+        }
+    }
+
+    fn observe_on_flush<F>(&self, name: &str, value: F)
+    where
+        F: Fn() -> isize + Send + Sync + 'static,
+    {
+        let original = sink::join(&self.path, name);
+        let name = sanitize(&original);
+        let entry = Entry {
+            kind: MetricKind::Gauge,
+            original,
+            value: Arc::new(OnceLock::from(Value::Observed(Box::new(value)))),
+            series: Arc::default(),
+        };
+        // The same as with the other metrics, the first one keeps the name.
+        self.metrics
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name)
+            .or_insert(entry);
+    }
+}
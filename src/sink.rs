@@ -0,0 +1,468 @@
+//! The interface to the metrics backends.
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter, Result as FmtResult};
+use std::ops::{Deref, Neg};
+use std::sync::{Arc, OnceLock};
+
+use dipstick::{InputKind, InputScope, Observe, Prefixed, WithAttributes};
+
+/// The kinds of metrics a [`Sink`] is asked to create.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum MetricKind {
+    /// Counts occurrences, the written values are added up.
+    Counter,
+    /// Marks an occurrence, the written value is always `1`.
+    Marker,
+    /// Each written value replaces the previous one.
+    Gauge,
+    /// The written values are deltas, moving the level up and down.
+    Level,
+    /// Each written value is a duration, in microseconds.
+    Timer,
+}
+
+/// A value written into a [`Metric`].
+///
+/// The values recorded as floating point numbers (eg. `metrics.gauge.load = 0.73`) are passed on
+/// as such, everything else (including the times of timers) is an integer.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MetricValue {
+    /// An integer value.
+    Int(i64),
+    /// A floating point value.
+    Float(f64),
+}
+
+impl MetricValue {
+    /// The value as a floating point number.
+    pub fn as_f64(self) -> f64 {
+        match self {
+            MetricValue::Int(value) => value as f64,
+            MetricValue::Float(value) => value,
+        }
+    }
+
+    /// The value as an integer.
+    ///
+    /// Floating point values are rounded to the nearest integer. Values out of the range of `i64`
+    /// saturate and `NaN` becomes `0`.
+    pub fn as_i64(self) -> i64 {
+        match self {
+            MetricValue::Int(value) => value,
+            MetricValue::Float(value) => value.round() as i64,
+        }
+    }
+}
+
+impl From<i64> for MetricValue {
+    fn from(value: i64) -> Self {
+        MetricValue::Int(value)
+    }
+}
+
+impl From<f64> for MetricValue {
+    fn from(value: f64) -> Self {
+        MetricValue::Float(value)
+    }
+}
+
+impl Neg for MetricValue {
+    type Output = Self;
+    fn neg(self) -> Self {
+        match self {
+            MetricValue::Int(value) => MetricValue::Int(value.saturating_neg()),
+            MetricValue::Float(value) => MetricValue::Float(-value),
+        }
+    }
+}
+
+/// A label (dimension) of a written value, from a `metrics.label.<key>` attribute.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct Label {
+    key: &'static str,
+    value: Arc<String>,
+}
+
+impl Label {
+    /// Creates the label.
+    pub fn new<V: Into<String>>(key: &'static str, value: V) -> Self {
+        Label {
+            key,
+            value: Arc::new(value.into()),
+        }
+    }
+
+    /// The key of the label (the part after `metrics.label.`).
+    pub fn key(&self) -> &'static str {
+        self.key
+    }
+
+    /// The value of the label.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+}
+
+/// The labels of a written value, collected from the enclosing spans.
+///
+/// They are unique by their keys and dereference to a slice of them. They are collected once for
+/// each span and shared by all the values written inside it, together with their conversions for
+/// the sinks (eg. into the [`dipstick::Labels`]).
+#[derive(Clone, Debug, Default)]
+pub struct Labels(Option<Arc<LabelsInner>>);
+
+#[derive(Debug)]
+struct LabelsInner {
+    labels: Box<[Label]>,
+    dipstick: OnceLock<dipstick::Labels>,
+}
+
+impl Labels {
+    /// The labels converted for [`dipstick`], only once.
+    fn dipstick(&self) -> dipstick::Labels {
+        let Some(inner) = &self.0 else {
+            return dipstick::Labels::default();
+        };
+        let labels = inner.dipstick.get_or_init(|| {
+            let map: HashMap<_, _> = inner
+                .labels
+                .iter()
+                .map(|label| (label.key.to_owned(), Arc::clone(&label.value)))
+                .collect();
+            dipstick::Labels::from(map)
+        });
+        labels.clone()
+    }
+}
+
+impl From<Vec<Label>> for Labels {
+    fn from(labels: Vec<Label>) -> Self {
+        if labels.is_empty() {
+            return Labels::default();
+        }
+        Labels(Some(Arc::new(LabelsInner {
+            labels: labels.into(),
+            dipstick: OnceLock::new(),
+        })))
+    }
+}
+
+impl Deref for Labels {
+    type Target = [Label];
+    fn deref(&self) -> &[Label] {
+        self.0
+            .as_ref()
+            .map(|inner| &inner.labels[..])
+            .unwrap_or_default()
+    }
+}
+
+type Write = dyn Fn(MetricValue, &Labels) + Send + Sync;
+
+/// A handle to a metric created by a [`Sink`].
+///
+/// It is created once and then written into many times, possibly from multiple threads.
+#[derive(Clone)]
+pub struct Metric(Arc<Write>);
+
+impl Metric {
+    /// Creates the metric from the function writing a value into it.
+    pub fn new<F>(write: F) -> Self
+    where
+        F: Fn(MetricValue, &Labels) + Send + Sync + 'static,
+    {
+        Metric(Arc::new(write))
+    }
+
+    /// Writes a value into the metric.
+    ///
+    /// The meaning depends on the [`MetricKind`] the metric was created as. The labels come from
+    /// the enclosing spans, they are unique by their keys.
+    pub fn write<V: Into<MetricValue>>(&self, value: V, labels: &Labels) {
+        (self.0)(value.into(), labels)
+    }
+}
+
+impl Debug for Metric {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        fmt.write_str("Metric")
+    }
+}
+
+/// A backend the metrics are sent into.
+///
+/// The [`DipstickLayer`][crate::DipstickLayer] interprets the `metrics.*` attributes and only
+/// asks the sink to create the metrics and the scopes for them. The labels are passed with each
+/// written value. Therefore, switching to another backend means only passing a different sink
+/// when initializing, the instrumentation stays the same.
+///
+/// The sink is the scope the metrics are created in. It is cloned into the nested scopes and the
+/// created metrics are cached, so creating them may be relatively expensive.
+///
+/// This is implemented for all the [`dipstick`] scopes (eg. the
+/// [`AtomicBucket`][dipstick::AtomicBucket]). The labels are passed to them as the per-metric
+/// [`Labels`][dipstick::Labels], so the thread and app labels of [`dipstick`] still apply. As
+/// these hold integers only, floating point values are rounded (see [`Scaled`] to keep some
+/// decimal places).
+///
+/// # Examples
+///
+/// ```rust
+/// use std::sync::atomic::{AtomicI64, Ordering};
+/// use std::sync::Arc;
+///
+/// use tracing::{debug, subscriber};
+/// use tracing_dipstick::{DipstickLayer, Metric, MetricKind, Sink};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// /// Counts everything into a single number, ignoring the names.
+/// #[derive(Clone, Default)]
+/// struct Total(Arc<AtomicI64>);
+///
+/// impl Sink for Total {
+///     fn new_metric(&self, _name: &str, _kind: MetricKind) -> Metric {
+///         let total = Arc::clone(&self.0);
+///         Metric::new(move |value, _labels| {
+///             total.fetch_add(value.as_i64(), Ordering::Relaxed);
+///         })
+///     }
+///     fn scope(&self, _name: &str) -> Self {
+///         self.clone()
+///     }
+///     fn scope_full(&self, _name: &str) -> Self {
+///         self.clone()
+///     }
+/// }
+///
+/// let total = Total::default();
+/// let subscriber = Registry::default().with(DipstickLayer::new(total.clone()));
+/// subscriber::with_default(subscriber, || {
+///     debug!(metrics.counter = "requests");
+///     debug!(metrics.counter.bytes = 41);
+/// });
+/// assert_eq!(42, total.0.load(Ordering::Relaxed));
+/// ```
+pub trait Sink: Clone + Send + Sync + 'static {
+    /// Creates a metric in this scope.
+    fn new_metric(&self, name: &str, kind: MetricKind) -> Metric;
+
+    /// Derives a nested scope, for `metrics.scope`.
+    ///
+    /// The names of the metrics inside are expected to be prefixed by the name of this scope and
+    /// then the given `name`.
+    fn scope(&self, name: &str) -> Self;
+
+    /// Derives a scope replacing the name, for `metrics.scope.full`.
+    ///
+    /// The names of the metrics inside are expected to be prefixed by the given `name` only.
+    fn scope_full(&self, name: &str) -> Self;
+
+    /// Registers a gauge, set to the given value whenever the sink publishes its metrics.
+    ///
+    /// This is used for the values that make sense only once for each publishing, like the
+    /// [extremes of levels][crate::DipstickLayer::level_max]. Registering a gauge of the same
+    /// name again replaces the previous one.
+    ///
+    /// The default does nothing, for sinks that don't publish in any intervals.
+    fn observe_on_flush<F>(&self, name: &str, value: F)
+    where
+        F: Fn() -> isize + Send + Sync + 'static,
+    {
+        let _ = (name, value);
+    }
+}
+
+/// Joins the name to the full name of a scope, separated by a dot.
+///
+/// The root scope has an empty name, the names in it are left as they are.
+pub(crate) fn join(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_owned()
+    } else {
+        format!("{}.{}", path, name)
+    }
+}
+
+/// The full name of a scope, for the sinks without scopes of their own.
+///
+/// It is either prefixed to the names of the metrics or, with a key, carried in a label of that
+/// key (or whatever the sink has for labels), leaving the names short. The metrics in the root
+/// scope get no such label.
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+#[derive(Clone, Debug)]
+pub(crate) struct ScopePath<K> {
+    /// Dot-separated.
+    path: String,
+    key: Option<K>,
+}
+
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+impl<K> Default for ScopePath<K> {
+    fn default() -> Self {
+        ScopePath {
+            path: String::new(),
+            key: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "metrics", feature = "opentelemetry"))]
+impl<K: Clone> ScopePath<K> {
+    /// Puts the path into a label of the given key instead of the names.
+    pub(crate) fn with_key(self, key: K) -> Self {
+        ScopePath {
+            key: Some(key),
+            ..self
+        }
+    }
+
+    /// The name of the metric and the label with the path, if the path is not in the name.
+    pub(crate) fn key(&self, name: &str) -> (String, Option<(K, String)>) {
+        match &self.key {
+            Some(key) if !self.path.is_empty() => {
+                (name.to_owned(), Some((key.clone(), self.path.clone())))
+            }
+            Some(_) => (name.to_owned(), None),
+            None => (join(&self.path, name), None),
+        }
+    }
+
+    /// See [`Sink::scope`].
+    pub(crate) fn scope(&self, name: &str) -> Self {
+        ScopePath {
+            path: join(&self.path, name),
+            key: self.key.clone(),
+        }
+    }
+
+    /// See [`Sink::scope_full`].
+    pub(crate) fn scope_full(&self, name: &str) -> Self {
+        ScopePath {
+            path: name.to_owned(),
+            key: self.key.clone(),
+        }
+    }
+}
+
+fn input_kind(kind: MetricKind) -> InputKind {
+    match kind {
+        MetricKind::Counter => InputKind::Counter,
+        MetricKind::Marker => InputKind::Marker,
+        MetricKind::Gauge => InputKind::Gauge,
+        MetricKind::Level => InputKind::Level,
+        MetricKind::Timer => InputKind::Timer,
+    }
+}
+
+/// Creates a [`dipstick`] metric, with the floating point values multiplied by the `scale`.
+fn dipstick_metric<T: InputScope>(scope: &T, name: &str, kind: MetricKind, scale: f64) -> Metric {
+    let metric = scope.new_metric(name.into(), input_kind(kind));
+    Metric::new(move |value, labels| {
+        let value = match value {
+            MetricValue::Int(value) => value as isize,
+            // The `as` saturates on overflow and turns NaN into 0.
+            MetricValue::Float(value) => (value * scale).round() as isize,
+        };
+        metric.write(value, labels.dipstick())
+    })
+}
+
+fn dipstick_observe<T>(scope: &T, name: &str, value: impl Fn() -> isize + Send + Sync + 'static)
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    let gauge = scope.new_metric(name.into(), InputKind::Gauge);
+    scope.observe(&gauge, move |_| value()).on_flush();
+}
+
+impl<T> Sink for T
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    fn new_metric(&self, name: &str, kind: MetricKind) -> Metric {
+        dipstick_metric(self, name, kind, 1.0)
+    }
+
+    fn scope(&self, name: &str) -> Self {
+        self.add_name(name)
+    }
+
+    fn scope_full(&self, name: &str) -> Self {
+        self.named(name)
+    }
+
+    fn observe_on_flush<F>(&self, name: &str, value: F)
+    where
+        F: Fn() -> isize + Send + Sync + 'static,
+    {
+        dipstick_observe(self, name, value);
+    }
+}
+
+/// A [`dipstick`] scope with scaled floating point values.
+///
+/// The metrics in [`dipstick`] hold integers only. Therefore, values recorded as `f64` (eg.
+/// `metrics.gauge.load = 0.73`) are multiplied by the scale and rounded to the nearest integer.
+/// Values out of the range of `i64` saturate and `NaN` becomes `0`. The integer values are left
+/// intact.
+///
+/// The scopes used directly as the [`Sink`] have the scale of `1.0`, which only rounds the values.
+/// To keep, for example, 3 decimal places, use `1000.0` (the above gauge would then be sent as
+/// `730`). The other sinks get the floating point values as they are.
+///
+/// # Examples
+///
+/// ```rust
+/// use dipstick::AtomicBucket;
+/// use tracing::subscriber;
+/// use tracing_dipstick::{DipstickLayer, Scaled};
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::Registry;
+///
+/// let bridge = DipstickLayer::new(Scaled::new(AtomicBucket::new(), 1000.0));
+/// subscriber::set_global_default(Registry::default().with(bridge)).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Scaled<T> {
+    scope: T,
+    scale: f64,
+}
+
+impl<T> Scaled<T> {
+    /// Wraps the scope, multiplying the floating point values by the `scale`.
+    pub fn new(scope: T, scale: f64) -> Self {
+        Scaled { scope, scale }
+    }
+
+    /// Provides access to the wrapped scope.
+    pub fn inner(&self) -> &T {
+        &self.scope
+    }
+}
+
+impl<T> Sink for Scaled<T>
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    fn new_metric(&self, name: &str, kind: MetricKind) -> Metric {
+        dipstick_metric(&self.scope, name, kind, self.scale)
+    }
+
+    fn scope(&self, name: &str) -> Self {
+        Scaled::new(self.scope.add_name(name), self.scale)
+    }
+
+    fn scope_full(&self, name: &str) -> Self {
+        Scaled::new(self.scope.named(name), self.scale)
+    }
+
+    fn observe_on_flush<F>(&self, name: &str, value: F)
+    where
+        F: Fn() -> isize + Send + Sync + 'static,
+    {
+        dipstick_observe(&self.scope, name, value);
+    }
+}
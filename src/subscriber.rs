@@ -0,0 +1,359 @@
+//! A [`Subscriber`] collecting metrics independently of the wrapped one.
+
+use std::cell::Cell;
+use std::fmt::Debug;
+use std::sync::RwLock;
+
+use tracing_core::field::{self, DisplayValue, Field, Value, ValueSet, Visit};
+use tracing_core::span::{Attributes, Current, Id, Record};
+use tracing_core::subscriber::Interest;
+use tracing_core::{Dispatch, Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::registry::{LookupSpan, Registry};
+
+use crate::plan::CallsiteMap;
+use crate::{has_metrics, read, write, DipstickLayer, Sink};
+
+/// The id of the same span in the inner subscriber.
+struct InnerId(Id);
+
+/// The parts of the [`DipstickSubscriber`].
+#[derive(Copy, Clone)]
+enum Part {
+    Inner,
+    Metrics,
+}
+
+thread_local! {
+    /// The part that is currently closing a span on this thread.
+    ///
+    /// The registries release the parents of closed spans through the default dispatcher, which
+    /// is the [`DipstickSubscriber`]. These re-entrant calls carry the ids of that part and need to
+    /// go back to it.
+    static CLOSING: Cell<Option<Part>> = const { Cell::new(None) };
+}
+
+/// Marks the part as closing for the duration of its `try_close` call.
+struct ClosingGuard(Option<Part>);
+
+impl ClosingGuard {
+    fn new(part: Part) -> Self {
+        ClosingGuard(CLOSING.with(|closing| closing.replace(Some(part))))
+    }
+}
+
+impl Drop for ClosingGuard {
+    fn drop(&mut self) {
+        CLOSING.with(|closing| closing.set(self.0));
+    }
+}
+
+/// A value of a field, captured from an event.
+enum Captured {
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+    Debug(DisplayValue<String>),
+}
+
+impl Captured {
+    fn value(&self) -> &dyn Value {
+        match self {
+            Captured::Bool(v) => v,
+            Captured::I64(v) => v,
+            Captured::U64(v) => v,
+            Captured::F64(v) => v,
+            Captured::Str(v) => v,
+            Captured::Debug(v) => v,
+        }
+    }
+}
+
+struct Captures(Vec<(Field, Captured)>);
+
+impl Captures {
+    fn with_value_set<R>(
+        &self,
+        metadata: &'static Metadata<'static>,
+        f: impl FnOnce(&ValueSet) -> R,
+    ) -> R {
+        let fields = metadata.fields();
+        // A slot for each field of the callsite, the ones not recorded have no value.
+        let mut values: Vec<Option<&dyn Value>> = vec![None; fields.len()];
+        for (field, value) in &self.0 {
+            values[field.index()] = Some(value.value());
+        }
+        f(&fields.value_set_all(&values))
+    }
+}
+
+impl Visit for Captures {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        let value = field::display(format!("{:?}", value));
+        self.0.push((field.clone(), Captured::Debug(value)));
+    }
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.push((field.clone(), Captured::Bool(value)));
+    }
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.push((field.clone(), Captured::I64(value)));
+    }
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.push((field.clone(), Captured::U64(value)));
+    }
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.push((field.clone(), Captured::F64(value)));
+    }
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0
+            .push((field.clone(), Captured::Str(value.to_owned())));
+    }
+}
+
+/// A [`Subscriber`] collecting metrics independently of the filtering of another subscriber.
+///
+/// This bypasses the [`Layer`][tracing_subscriber::Layer] system. It wraps an inner subscriber
+/// (for example one doing logging, with all its filtering) and intercepts every callsite that
+/// carries metrics. These are collected by the given [`DipstickLayer`], while the inner subscriber
+/// gets only the spans and events its own filtering wants. The callsites without metrics are left
+/// up to the inner subscriber completely.
+///
+/// Unlike with the [`DipstickLayer`] placed into a shared [`Registry`], the metrics are collected
+/// even if the inner subscriber filters globally (for example with a level filter layer).
+///
+/// The span ids handed out are the ones of this subscriber. They are translated to the ids of the
+/// inner subscriber when forwarding to it.
+///
+/// # Limitations
+///
+/// As the ids differ, the inner subscriber can't be reached from the outside. Downcasting (eg.
+/// through [`Dispatch::downcast_ref`]) finds only the [`DipstickSubscriber`] itself, not the inner
+/// subscriber or its layers. Extensions that look up the current span in their layer this way
+/// (like `OpenTelemetrySpanExt` of `tracing-opentelemetry`) don't work with the wrapped
+/// subscriber. Place such layers next to the [`DipstickLayer`] in a shared [`Registry`] instead.
+///
+/// # Examples
+///
+/// ```rust
+/// use dipstick::AtomicBucket;
+/// use tracing::{debug, subscriber};
+/// use tracing_dipstick::{DipstickLayer, DipstickSubscriber};
+/// use tracing_subscriber::filter::LevelFilter;
+/// use tracing_subscriber::layer::SubscriberExt;
+/// use tracing_subscriber::{fmt, Registry};
+///
+/// let logging = Registry::default()
+///     .with(fmt::layer())
+///     .with(LevelFilter::INFO);
+/// let bridge = DipstickLayer::new(AtomicBucket::new());
+/// let subscriber = DipstickSubscriber::new(bridge, logging);
+///
+/// subscriber::set_global_default(subscriber).unwrap();
+///
+/// debug!(metrics.counter = "requests", "Counted, but not logged");
+/// ```
+#[derive(Debug)]
+pub struct DipstickSubscriber<S, Inner> {
+    metrics: Layered<DipstickLayer<S>, Registry>,
+    inner: Inner,
+    /// The callsites with metrics, together with the information if the inner subscriber may be
+    /// interested in them.
+    callsites: RwLock<CallsiteMap<bool>>,
+}
+
+impl<S, Inner> DipstickSubscriber<S, Inner>
+where
+    S: Sink,
+    Inner: Subscriber,
+{
+    /// Creates the subscriber.
+    ///
+    /// The metrics are collected by the `layer`, everything is forwarded to the `inner`
+    /// subscriber (subject to its filtering).
+    pub fn new(layer: DipstickLayer<S>, inner: Inner) -> Self {
+        DipstickSubscriber {
+            metrics: Registry::default().with(layer),
+            inner,
+            callsites: RwLock::default(),
+        }
+    }
+
+    /// Provides access to the wrapped subscriber.
+    pub fn inner(&self) -> &Inner {
+        &self.inner
+    }
+
+    /// Looks up a callsite with metrics.
+    ///
+    /// Returns if the inner subscriber may be interested in it, or `None` for callsites without
+    /// metrics.
+    fn metric_callsite(&self, metadata: &'static Metadata<'static>) -> Option<bool> {
+        let known = read(&self.callsites).get(&metadata.callsite()).copied();
+        known.or_else(|| has_metrics(metadata).then_some(true))
+    }
+
+    fn inner_id(&self, id: &Id) -> Option<Id> {
+        let span = self.metrics.span(id)?;
+        let extensions = span.extensions();
+        extensions.get::<InnerId>().map(|inner| inner.0.clone())
+    }
+
+    /// Finds the nearest span (starting with the given one) the inner subscriber knows about.
+    fn inner_parent(&self, id: &Id) -> Option<Id> {
+        self.metrics.span(id)?.scope().find_map(|span| {
+            let extensions = span.extensions();
+            extensions.get::<InnerId>().map(|inner| inner.0.clone())
+        })
+    }
+
+    fn forward_span(&self, span: &Attributes) -> Id {
+        match span.parent() {
+            Some(parent) => {
+                let (metadata, values) = (span.metadata(), span.values());
+                let translated = match self.inner_parent(parent) {
+                    Some(parent) => Attributes::child_of(parent, metadata, values),
+                    None => Attributes::new_root(metadata, values),
+                };
+                self.inner.new_span(&translated)
+            }
+            None => self.inner.new_span(span),
+        }
+    }
+
+    fn forward_event(&self, event: &Event) {
+        match event.parent() {
+            Some(parent) => {
+                let parent = self.inner_parent(parent);
+                let mut captures = Captures(Vec::new());
+                event.record(&mut captures);
+                let metadata = event.metadata();
+                captures.with_value_set(metadata, |values| {
+                    self.inner
+                        .event(&Event::new_child_of(parent, metadata, values))
+                });
+            }
+            None => self.inner.event(event),
+        }
+    }
+}
+
+impl<S, Inner> Subscriber for DipstickSubscriber<S, Inner>
+where
+    S: Sink,
+    Inner: Subscriber,
+{
+    fn on_register_dispatch(&self, subscriber: &Dispatch) {
+        self.metrics.on_register_dispatch(subscriber);
+        self.inner.on_register_dispatch(subscriber);
+    }
+
+    fn register_callsite(&self, metadata: &'static Metadata<'static>) -> Interest {
+        let inner = self.inner.register_callsite(metadata);
+        self.metrics.register_callsite(metadata);
+        if has_metrics(metadata) {
+            write(&self.callsites).insert(metadata.callsite(), !inner.is_never());
+            Interest::always()
+        } else {
+            inner
+        }
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        read(&self.callsites).contains_key(&metadata.callsite()) || self.inner.enabled(metadata)
+    }
+
+    fn event_enabled(&self, event: &Event) -> bool {
+        self.metric_callsite(event.metadata()).is_some() || self.inner.event_enabled(event)
+    }
+
+    fn new_span(&self, span: &Attributes) -> Id {
+        let metadata = span.metadata();
+        let forward = match self.metric_callsite(metadata) {
+            Some(interested) => interested && self.inner.enabled(metadata),
+            // The inner subscriber has already decided through the interest or enabled.
+            None => true,
+        };
+        let inner = forward.then(|| self.forward_span(span));
+        let id = self.metrics.new_span(span);
+        if let Some(inner) = inner {
+            self.metrics
+                .span(&id)
+                .expect("Missing newly created span")
+                .extensions_mut()
+                .insert(InnerId(inner));
+        }
+        id
+    }
+
+    fn record(&self, span: &Id, values: &Record) {
+        if let Some(inner) = self.inner_id(span) {
+            self.inner.record(&inner, values);
+        }
+        self.metrics.record(span, values);
+    }
+
+    fn record_follows_from(&self, span: &Id, follows: &Id) {
+        if let (Some(inner), Some(inner_follows)) = (self.inner_id(span), self.inner_id(follows)) {
+            self.inner.record_follows_from(&inner, &inner_follows);
+        }
+        self.metrics.record_follows_from(span, follows);
+    }
+
+    fn event(&self, event: &Event) {
+        let metadata = event.metadata();
+        let forward = match self.metric_callsite(metadata) {
+            Some(interested) => {
+                self.metrics.event(event);
+                interested && self.inner.enabled(metadata) && self.inner.event_enabled(event)
+            }
+            // The inner subscriber has already decided through the interest or enabled.
+            None => true,
+        };
+        if forward {
+            self.forward_event(event);
+        }
+    }
+
+    fn enter(&self, span: &Id) {
+        self.metrics.enter(span);
+        if let Some(inner) = self.inner_id(span) {
+            self.inner.enter(&inner);
+        }
+    }
+
+    fn exit(&self, span: &Id) {
+        if let Some(inner) = self.inner_id(span) {
+            self.inner.exit(&inner);
+        }
+        self.metrics.exit(span);
+    }
+
+    fn clone_span(&self, id: &Id) -> Id {
+        if let Some(inner) = self.inner_id(id) {
+            // We keep using the original inner id, the clone only bumps the reference count.
+            self.inner.clone_span(&inner);
+        }
+        self.metrics.clone_span(id)
+    }
+
+    fn try_close(&self, id: Id) -> bool {
+        match CLOSING.with(Cell::get) {
+            Some(Part::Inner) => return self.inner.try_close(id),
+            Some(Part::Metrics) => return self.metrics.try_close(id),
+            None => (),
+        }
+        // Needs to be looked up before the span possibly disappears.
+        if let Some(inner) = self.inner_id(&id) {
+            let _guard = ClosingGuard::new(Part::Inner);
+            self.inner.try_close(inner);
+        }
+        let _guard = ClosingGuard::new(Part::Metrics);
+        self.metrics.try_close(id)
+    }
+
+    fn current_span(&self) -> Current {
+        self.metrics.current_span()
+    }
+}
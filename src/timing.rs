@@ -0,0 +1,135 @@
+//! Measuring how spans spend their time.
+
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+
+/// The CPU time consumed by the current thread so far.
+///
+/// Returns `None` if it can't be measured (on unsupported platforms).
+#[cfg(any(unix, windows))]
+fn thread_time() -> Option<Duration> {
+    cpu_time::ThreadTime::try_now()
+        .ok()
+        .map(|time| time.as_duration())
+}
+
+#[cfg(not(any(unix, windows)))]
+fn thread_time() -> Option<Duration> {
+    None
+}
+
+/// Accumulates the CPU time spent inside a span.
+///
+/// The span may be entered on multiple threads (even at once) and multiple times on the same
+/// thread. Each thread is measured separately, from the outermost enter to the matching exit.
+#[derive(Debug, Default)]
+pub(crate) struct CpuTime {
+    spent: Duration,
+    /// The threads currently inside the span, how many times and their CPU time at the first
+    /// enter.
+    entered: Vec<(ThreadId, usize, Duration)>,
+}
+
+impl CpuTime {
+    pub(crate) fn enter(&mut self) {
+        let current = thread::current().id();
+        match self.entered.iter_mut().find(|(id, _, _)| *id == current) {
+            Some((_, depth, _)) => *depth += 1,
+            None => {
+                if let Some(start) = thread_time() {
+                    self.entered.push((current, 1, start));
+                }
+            }
+        }
+    }
+
+    pub(crate) fn exit(&mut self) {
+        let current = thread::current().id();
+        let Some(pos) = self.entered.iter().position(|(id, _, _)| *id == current) else {
+            return;
+        };
+        let (_, depth, start) = &mut self.entered[pos];
+        *depth -= 1;
+        if *depth == 0 {
+            let start = *start;
+            self.entered.swap_remove(pos);
+            if let Some(now) = thread_time() {
+                self.spent += now.saturating_sub(start);
+            }
+        }
+    }
+
+    /// The CPU time spent inside the span so far (not counting the currently entered parts).
+    pub(crate) fn spent(&self) -> Duration {
+        self.spent
+    }
+}
+
+/// Accumulates the (wall clock) time during which a span is entered.
+///
+/// The span is busy while it is entered at least once, on any thread. The rest of its lifetime
+/// it is idle.
+#[derive(Debug)]
+pub(crate) struct BusyTime {
+    created: Instant,
+    busy: Duration,
+    /// How many times the span is currently entered, across all threads.
+    entered: usize,
+    /// When the span became busy the last time.
+    since: Instant,
+    /// The time between the creation and the first enter.
+    queued: Option<Duration>,
+}
+
+impl BusyTime {
+    pub(crate) fn new() -> Self {
+        let now = Instant::now();
+        BusyTime {
+            created: now,
+            busy: Duration::ZERO,
+            entered: 0,
+            since: now,
+            queued: None,
+        }
+    }
+
+    pub(crate) fn enter(&mut self) {
+        if self.entered == 0 {
+            self.since = Instant::now();
+            self.queued
+                .get_or_insert_with(|| self.since.duration_since(self.created));
+        }
+        self.entered += 1;
+    }
+
+    pub(crate) fn exit(&mut self) {
+        if self.entered == 1 {
+            self.busy += self.since.elapsed();
+        }
+        self.entered = self.entered.saturating_sub(1);
+    }
+
+    /// The time the span was busy so far.
+    pub(crate) fn busy(&self) -> Duration {
+        if self.entered > 0 {
+            self.busy + self.since.elapsed()
+        } else {
+            self.busy
+        }
+    }
+
+    /// The time since the creation of the span.
+    pub(crate) fn lifetime(&self) -> Duration {
+        self.created.elapsed()
+    }
+
+    /// The time the span waited to be entered the first time, if it already was.
+    pub(crate) fn queued(&self) -> Option<Duration> {
+        self.queued
+    }
+
+    /// The time the span was idle so far.
+    pub(crate) fn idle(&self) -> Duration {
+        self.lifetime().saturating_sub(self.busy())
+    }
+}
@@ -0,0 +1,108 @@
+//! Helpers shared by the tests.
+#![allow(dead_code)]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use dipstick::{
+    Attributes, Flush, InputKind, InputMetric, InputScope, MetricId, MetricName, OnFlush, Prefixed,
+    WithAttributes,
+};
+use tracing::{info_span, Dispatch, Span};
+use tracing_dipstick::{DipstickLayer, DipstickSubscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+/// A scope summing up everything written into the metrics, by their full names.
+///
+/// Gauges keep the last value instead. The `tenant` label, if any, is appended to the name.
+#[derive(Clone, Default)]
+pub struct Sums {
+    attributes: Attributes,
+    sums: Arc<Mutex<HashMap<String, isize>>>,
+    kinds: Arc<Mutex<HashMap<String, InputKind>>>,
+}
+
+impl Sums {
+    pub fn get(&self, name: &str) -> isize {
+        self.sums.lock().unwrap().get(name).copied().unwrap_or(0)
+    }
+
+    /// Was the metric created?
+    pub fn contains(&self, name: &str) -> bool {
+        self.sums.lock().unwrap().contains_key(name)
+    }
+
+    /// The kind the metric was created with.
+    pub fn kind(&self, name: &str) -> Option<InputKind> {
+        self.kinds.lock().unwrap().get(name).copied()
+    }
+}
+
+impl WithAttributes for Sums {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Flush for Sums {
+    fn flush(&self) -> std::io::Result<()> {
+        self.notify_flush_listeners();
+        Ok(())
+    }
+}
+
+impl InputScope for Sums {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_prepend(name).join(".");
+        self.sums.lock().unwrap().entry(name.clone()).or_default();
+        self.kinds.lock().unwrap().insert(name.clone(), kind);
+        let sums = Arc::clone(&self.sums);
+        InputMetric::new(
+            MetricId::forge("sums", name.clone().into()),
+            move |value, labels| {
+                let name = match labels.lookup("tenant") {
+                    Some(tenant) => format!("{}{{tenant={}}}", name, tenant),
+                    None => name.clone(),
+                };
+                let mut sums = sums.lock().unwrap();
+                let sum = sums.entry(name).or_default();
+                match kind {
+                    InputKind::Gauge => *sum = value,
+                    _ => *sum += value,
+                }
+            },
+        )
+    }
+}
+
+/// The layer in a registry.
+pub fn layered() -> (Sums, Dispatch) {
+    let sums = Sums::default();
+    let subscriber = Registry::default().with(DipstickLayer::new(sums.clone()));
+    (sums, Dispatch::new(subscriber))
+}
+
+/// The layer in a [`DipstickSubscriber`], wrapping a subscriber that filters everything out.
+pub fn wrapped() -> (Sums, Dispatch) {
+    let sums = Sums::default();
+    let inner = Registry::default().with(LevelFilter::WARN);
+    let subscriber = DipstickSubscriber::new(DipstickLayer::new(sums.clone()), inner);
+    (sums, Dispatch::new(subscriber))
+}
+
+/// Runs the test with both the [`layered`] and [`wrapped`] setups.
+pub fn both(test: impl Fn(Sums, Dispatch)) {
+    let (sums, dispatch) = layered();
+    test(sums, dispatch);
+    let (sums, dispatch) = wrapped();
+    test(sums, dispatch);
+}
+
+pub fn active() -> Span {
+    info_span!("Active", metrics.scope = "scope", metrics.level = "active")
+}
@@ -0,0 +1,76 @@
+//! Counters and markers.
+
+mod common;
+
+use dipstick::InputKind;
+use tracing::{debug, dispatcher, info_span};
+
+use common::both;
+
+#[test]
+fn counted_on_close() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = info_span!(
+                "Job",
+                metrics.counter = "started",
+                metrics.counter.on_close = "finished"
+            );
+            let clone = span.clone();
+            drop(span);
+            assert_eq!(1, sums.get("started"));
+            assert_eq!(0, sums.get("finished"));
+            drop(clone);
+            assert_eq!(1, sums.get("finished"));
+            // Not mistaken for a counter named on_close.
+            assert_eq!(0, sums.get("on_close"));
+        });
+    });
+}
+
+#[test]
+fn named_by_values() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            // The same callsite naming different metrics.
+            for name in ["first", "second", "first", "third", "first"] {
+                debug!(metrics.counter = name);
+            }
+        });
+        assert_eq!(3, sums.get("first"));
+        assert_eq!(1, sums.get("second"));
+        assert_eq!(1, sums.get("third"));
+    });
+}
+
+#[test]
+fn marked() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let _span = info_span!("Yak", metrics.marker = "yaks").entered();
+            debug!(metrics.marker = "hair");
+            debug!(metrics.marker = "hair");
+        });
+        assert_eq!(1, sums.get("yaks"));
+        assert_eq!(2, sums.get("hair"));
+        assert_eq!(Some(InputKind::Marker), sums.kind("yaks"));
+        assert_eq!(Some(InputKind::Marker), sums.kind("hair"));
+    });
+}
+
+#[test]
+fn named_by_many_values() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            // More names than are cached, the ones over the limit are still counted.
+            for _ in 0..2 {
+                for id in 0..300 {
+                    debug!(metrics.counter = id.to_string().as_str());
+                }
+            }
+        });
+        for id in [0, 255, 256, 299] {
+            assert_eq!(2, sums.get(&id.to_string()), "{}", id);
+        }
+    });
+}
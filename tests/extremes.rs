@@ -0,0 +1,37 @@
+//! The extremes of levels, published with each flush.
+
+mod common;
+
+use dipstick::Flush;
+use tracing::{dispatcher, Dispatch};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+use common::{active, Sums};
+
+#[test]
+fn extremes_per_flush() {
+    let sums = Sums::default();
+    let bridge = DipstickLayer::new(sums.clone()).level_max().level_min();
+    let dispatch = Dispatch::new(Registry::default().with(bridge));
+    dispatcher::with_default(&dispatch, || {
+        let outer = active();
+        let spike = (0..3).map(|_| active()).collect::<Vec<_>>();
+        drop(spike);
+        sums.flush().unwrap();
+        assert_eq!(1, sums.get("scope.active"));
+        assert_eq!(4, sums.get("scope.active.max"));
+        assert_eq!(0, sums.get("scope.active.min"));
+
+        // Starts from the current value after the flush.
+        sums.flush().unwrap();
+        assert_eq!(1, sums.get("scope.active.max"));
+        assert_eq!(1, sums.get("scope.active.min"));
+
+        drop(outer);
+        sums.flush().unwrap();
+        assert_eq!(1, sums.get("scope.active.max"));
+        assert_eq!(0, sums.get("scope.active.min"));
+    });
+}
@@ -0,0 +1,93 @@
+//! Sending the metrics into the `metrics` facade.
+#![cfg(feature = "metrics")]
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+use tracing::{debug, dispatcher, info_span, Dispatch};
+use tracing_dipstick::{DipstickLayer, MetricsFacade};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+/// Runs the instrumentation with the sink and collects the metrics by their names and labels.
+fn collect(sink: MetricsFacade, f: impl FnOnce()) -> HashMap<String, DebugValue> {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    metrics::with_local_recorder(&recorder, || {
+        let dispatch = Dispatch::new(Registry::default().with(DipstickLayer::new(sink)));
+        dispatcher::with_default(&dispatch, f);
+    });
+    snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, _, _, value)| {
+            let (_, key) = key.into_parts();
+            let labels = key
+                .labels()
+                .map(|label| format!("{{{}={}}}", label.key(), label.value()))
+                .collect::<String>();
+            (format!("{}{}", key.name(), labels), value)
+        })
+        .collect()
+}
+
+fn yak() {
+    let _shaving = info_span!("Shaving", metrics.scope = "shaving").entered();
+    let _yak = info_span!(
+        "Yak",
+        metrics.scope = "yak",
+        metrics.timer = "time",
+        metrics.level = "active",
+        metrics.gauge.legs = 4,
+    )
+    .entered();
+    debug!(metrics.counter.hair = 3);
+    debug!(metrics.marker = "done");
+    thread::sleep(Duration::from_millis(10));
+}
+
+#[test]
+fn dotted() {
+    let metrics = collect(MetricsFacade::new(), yak);
+    assert_eq!(metrics["shaving.yak.hair"], DebugValue::Counter(3));
+    assert_eq!(metrics["shaving.yak.done"], DebugValue::Counter(1));
+    assert_eq!(metrics["shaving.yak.legs"], DebugValue::Gauge(4.0.into()));
+    assert_eq!(metrics["shaving.yak.active"], DebugValue::Gauge(0.0.into()));
+    match &metrics["shaving.yak.time"] {
+        DebugValue::Histogram(times) => {
+            assert_eq!(1, times.len());
+            assert!(times[0].into_inner() >= 0.01);
+            assert!(times[0].into_inner() < 10.0);
+        }
+        other => panic!("Timer not a histogram: {:?}", other),
+    }
+}
+
+#[test]
+fn labeled() {
+    let metrics = collect(MetricsFacade::new().scope_label("scope"), || {
+        yak();
+        debug!(metrics.counter = "outside");
+        let _full = info_span!("Full", metrics.scope.full = "other").entered();
+        debug!(metrics.counter = "inside");
+    });
+    assert_eq!(metrics["hair{scope=shaving.yak}"], DebugValue::Counter(3));
+    assert_eq!(metrics["outside"], DebugValue::Counter(1));
+    assert_eq!(metrics["inside{scope=other}"], DebugValue::Counter(1));
+}
+
+#[test]
+fn floats() {
+    let metrics = collect(MetricsFacade::new(), || {
+        debug!(metrics.gauge.load = 0.73);
+        debug!(metrics.level.queue = 1.5);
+        debug!(metrics.counter.hair = 2.6);
+    });
+    assert_eq!(metrics["load"], DebugValue::Gauge(0.73.into()));
+    assert_eq!(metrics["queue"], DebugValue::Gauge(1.5.into()));
+    // Counters hold integers.
+    assert_eq!(metrics["hair"], DebugValue::Counter(3));
+}
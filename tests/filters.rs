@@ -0,0 +1,68 @@
+//! The bridge alongside other layers with their own filtering.
+
+mod common;
+
+use tracing::subscriber::Interest;
+use tracing::{debug, dispatcher, info, info_span, trace, trace_span, Dispatch, Span};
+use tracing_dipstick::{DipstickLayer, MetricsCallsiteFilter};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Filter, SubscriberExt};
+use tracing_subscriber::{fmt, EnvFilter, Layer, Registry};
+
+use common::Sums;
+
+#[test]
+fn per_layer_env_filter() {
+    let sums = Sums::default();
+    let logging = fmt::layer()
+        .with_writer(std::io::sink)
+        .with_filter(EnvFilter::new("info"));
+    let subscriber = Registry::default()
+        .with(DipstickLayer::new(sums.clone()))
+        .with(logging);
+    dispatcher::with_default(&Dispatch::new(subscriber), || {
+        trace_span!("Verbose", metrics.scope = "verbose").in_scope(|| {
+            debug!(metrics.counter = "debug");
+            trace!(metrics.counter = "trace");
+            info!(metrics.counter = "info");
+        });
+    });
+    // The filter of the other layer doesn't hide anything from the bridge.
+    assert_eq!(1, sums.get("verbose.debug"));
+    assert_eq!(1, sums.get("verbose.trace"));
+    assert_eq!(1, sums.get("verbose.info"));
+}
+
+#[test]
+fn callsite_filter() {
+    let sums = Sums::default();
+    let bridge = DipstickLayer::new(sums.clone()).with_filter(MetricsCallsiteFilter::new());
+    let logging = fmt::layer()
+        .with_writer(std::io::sink)
+        .with_filter(LevelFilter::INFO);
+    let subscriber = Registry::default().with(bridge).with(logging);
+    dispatcher::with_default(&Dispatch::new(subscriber), || {
+        trace_span!("Scoped", metrics.scope = "scoped").in_scope(|| {
+            trace!(metrics.counter = "polls", "Polled");
+        });
+    });
+    assert_eq!(1, sums.get("scoped.polls"));
+}
+
+#[test]
+fn callsite_interest() {
+    fn interest(span: Span) -> Interest {
+        let metadata = span.metadata().expect("Missing metadata");
+        Filter::<Registry>::callsite_enabled(&MetricsCallsiteFilter::new(), metadata)
+    }
+
+    // Any subscriber enabling the spans, so they keep their metadata.
+    dispatcher::with_default(&Dispatch::new(Registry::default()), || {
+        assert!(interest(info_span!("Plain")).is_never());
+        assert!(interest(info_span!("Plain", other = 1)).is_never());
+        // Needed for the nesting, even without any metric of its own.
+        assert!(interest(info_span!("Scoped", metrics.scope = "scoped")).is_always());
+        assert!(interest(info_span!("Full", metrics.scope.full = "full")).is_always());
+        assert!(interest(info_span!("Timed", metrics.timer = "time")).is_always());
+    });
+}
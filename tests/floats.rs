@@ -0,0 +1,74 @@
+//! The floating point values, rounded (and possibly scaled) by the dipstick scopes.
+
+mod common;
+
+use tracing::{debug, dispatcher, info_span, Dispatch};
+use tracing_dipstick::{DipstickLayer, MetricValue, Scaled};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+use common::{both, Sums};
+
+#[test]
+fn rounded() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            debug!(metrics.counter.hair = 2.5);
+            debug!(metrics.counter.hair = 0.4);
+            debug!(metrics.gauge.load = 0.73);
+            debug!(metrics.gauge.down = -2.5);
+            // The levels are decremented by the rounded value too.
+            let span = info_span!("Yak", metrics.level.inflight = 1.6);
+            assert_eq!(2, sums.get("inflight"));
+            drop(span);
+        });
+        assert_eq!(3, sums.get("hair"));
+        assert_eq!(1, sums.get("load"));
+        assert_eq!(-3, sums.get("down"));
+        assert_eq!(0, sums.get("inflight"));
+    });
+}
+
+#[test]
+fn scaled() {
+    let sums = Sums::default();
+    let bridge = DipstickLayer::new(Scaled::new(sums.clone(), 1000.0));
+    dispatcher::with_default(&Dispatch::new(Registry::default().with(bridge)), || {
+        info_span!("Shaving", metrics.scope = "shaving").in_scope(|| {
+            debug!(metrics.gauge.load = 0.73);
+            debug!(metrics.counter.hair = 0.0004);
+            // Integers are left alone.
+            debug!(metrics.counter.legs = 4);
+        });
+    });
+    assert_eq!(730, sums.get("shaving.load"));
+    assert_eq!(0, sums.get("shaving.hair"));
+    assert_eq!(4, sums.get("shaving.legs"));
+}
+
+#[test]
+fn saturated() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            debug!(metrics.gauge.huge = 1e300);
+            debug!(metrics.gauge.tiny = f64::NEG_INFINITY);
+            debug!(metrics.gauge.nan = 5);
+            debug!(metrics.gauge.nan = f64::NAN);
+        });
+        assert_eq!(isize::MAX, sums.get("huge"));
+        assert_eq!(isize::MIN, sums.get("tiny"));
+        assert_eq!(0, sums.get("nan"));
+    });
+}
+
+#[test]
+fn converted() {
+    assert_eq!(3, MetricValue::Float(2.5).as_i64());
+    assert_eq!(-3, MetricValue::Float(-2.5).as_i64());
+    assert_eq!(i64::MAX, MetricValue::Float(f64::INFINITY).as_i64());
+    assert_eq!(i64::MIN, MetricValue::Float(-1e300).as_i64());
+    assert_eq!(0, MetricValue::Float(f64::NAN).as_i64());
+    assert_eq!(0.73, MetricValue::Float(0.73).as_f64());
+    assert_eq!(-42.0, MetricValue::Int(-42).as_f64());
+    assert_eq!(MetricValue::Int(i64::MAX), -MetricValue::Int(i64::MIN));
+}
@@ -0,0 +1,35 @@
+//! Labels from the `metrics.label.*` attributes, inherited by the nested spans and events.
+
+mod common;
+
+use tracing::{debug, dispatcher, info_span};
+
+use common::{active, both};
+
+#[test]
+fn labeled() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let tenant = info_span!(
+                "Tenant",
+                metrics.label.tenant = "yaks",
+                metrics.level = "active",
+            );
+            tenant.in_scope(|| {
+                let _inner = active().entered();
+                debug!(metrics.counter = "requests");
+                info_span!("Other", metrics.label.tenant = 42)
+                    .in_scope(|| debug!(metrics.counter = "requests"));
+                assert_eq!(1, sums.get("active{tenant=yaks}"));
+                assert_eq!(1, sums.get("scope.active{tenant=yaks}"));
+            });
+            debug!(metrics.counter = "requests");
+            drop(tenant);
+            assert_eq!(0, sums.get("active{tenant=yaks}"));
+            assert_eq!(0, sums.get("scope.active{tenant=yaks}"));
+        });
+        assert_eq!(1, sums.get("scope.requests{tenant=yaks}"));
+        assert_eq!(1, sums.get("scope.requests{tenant=42}"));
+        assert_eq!(1, sums.get("requests"));
+    });
+}
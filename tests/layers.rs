@@ -0,0 +1,36 @@
+//! Several bridges in the same subscriber.
+
+mod common;
+
+use tracing::{debug, dispatcher, Dispatch};
+use tracing_dipstick::DipstickLayer;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+use common::{active, Sums};
+
+#[test]
+fn two_layers() {
+    let (first, second) = (Sums::default(), Sums::default());
+    let subscriber = Registry::default()
+        .with(DipstickLayer::new(first.clone()))
+        .with(DipstickLayer::new(second.clone()));
+    dispatcher::with_default(&Dispatch::new(subscriber), || {
+        let span = active();
+        let clone = span.clone();
+        span.in_scope(|| {
+            debug!(metrics.counter = "shaved");
+            debug!(metrics.level.inc = "inflight");
+        });
+        assert_eq!(1, first.get("scope.active"));
+        assert_eq!(1, second.get("scope.active"));
+        drop(span);
+        clone.in_scope(|| debug!(metrics.level.dec = "inflight"));
+    });
+    for sums in [first, second] {
+        // Each layer gets its own copy of everything, not shared or counted twice.
+        assert_eq!(1, sums.get("scope.shaved"));
+        assert_eq!(0, sums.get("scope.active"));
+        assert_eq!(0, sums.get("scope.inflight"));
+    }
+}
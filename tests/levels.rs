@@ -0,0 +1,258 @@
+//! The levels on spans need to return to zero once all the handles to the spans are gone, no
+//! matter how they are cloned, passed between threads or dropped by panics.
+
+mod common;
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use dipstick::{Attributes, Flush, InputKind, InputMetric, InputScope, MetricName, WithAttributes};
+use tracing::{debug, dispatcher, field, info_span, Dispatch};
+use tracing_dipstick::{DipstickLayer, LevelLeak};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+use common::{active, both, Sums};
+
+#[test]
+fn closed_span() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = active();
+            assert_eq!(1, sums.get("scope.active"));
+            span.in_scope(|| debug!("Inside"));
+            drop(span);
+            assert_eq!(0, sums.get("scope.active"));
+        });
+    });
+}
+
+#[test]
+fn never_entered() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || drop(active()));
+        assert_eq!(0, sums.get("scope.active"));
+    });
+}
+
+#[test]
+fn clones_keep_open() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = active();
+            let clone = span.clone();
+            drop(span);
+            assert_eq!(1, sums.get("scope.active"));
+            // Entering through the clone after the original is gone.
+            clone.in_scope(|| debug!(metrics.counter = "inside"));
+            assert_eq!(1, sums.get("scope.inside"));
+            drop(clone);
+            assert_eq!(0, sums.get("scope.active"));
+        });
+    });
+}
+
+#[test]
+fn child_keeps_parent_open() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let parent = active();
+            let child = info_span!(parent: &parent, "Child", metrics.level = "child");
+            drop(parent);
+            assert_eq!(1, sums.get("scope.active"));
+            drop(child);
+            assert_eq!(0, sums.get("scope.active"));
+            assert_eq!(0, sums.get("scope.child"));
+        });
+    });
+}
+
+#[test]
+fn recorded_again() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = info_span!("Rows", metrics.level.rows = field::Empty);
+            span.record("metrics.level.rows", 5);
+            span.record("metrics.level.rows", 2);
+            assert_eq!(2, sums.get("rows"));
+            drop(span);
+            assert_eq!(0, sums.get("rows"));
+        });
+    });
+}
+
+#[test]
+fn clones_on_threads() {
+    both(|sums, dispatch| {
+        let span = dispatcher::with_default(&dispatch, active);
+        let workers = (0..8)
+            .map(|_| {
+                let span = span.clone();
+                let dispatch = dispatch.clone();
+                thread::spawn(move || {
+                    dispatcher::with_default(&dispatch, || {
+                        for _ in 0..100 {
+                            let _entered = span.enter();
+                            let _inner = active().entered();
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(span);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(0, sums.get("scope.active"));
+        assert_eq!(0, sums.get("scope.scope.active"));
+    });
+}
+
+#[test]
+fn panic_inside() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let result = panic::catch_unwind(|| {
+                let _span = active().entered();
+                let _inner = active().entered();
+                panic!("Oops");
+            });
+            assert!(result.is_err());
+        });
+        assert_eq!(0, sums.get("scope.active"));
+        assert_eq!(0, sums.get("scope.scope.active"));
+    });
+}
+
+#[test]
+fn panic_in_thread() {
+    both(|sums, dispatch| {
+        let span = dispatcher::with_default(&dispatch, active);
+        let worker = {
+            let span = span.clone();
+            let dispatch = dispatch.clone();
+            thread::spawn(move || {
+                dispatcher::with_default(&dispatch, || {
+                    let _entered = span.enter();
+                    let _inner = active().entered();
+                    panic!("Oops");
+                })
+            })
+        };
+        assert!(worker.join().is_err());
+        assert_eq!(1, sums.get("scope.active"));
+        drop(span);
+        assert_eq!(0, sums.get("scope.active"));
+        assert_eq!(0, sums.get("scope.scope.active"));
+    });
+}
+
+#[test]
+fn panic_in_backend() {
+    // A backend panicking while creating a metric must not break the following metrics.
+    #[derive(Clone, Default)]
+    struct Panicky(Sums);
+
+    impl WithAttributes for Panicky {
+        fn get_attributes(&self) -> &Attributes {
+            self.0.get_attributes()
+        }
+        fn mut_attributes(&mut self) -> &mut Attributes {
+            self.0.mut_attributes()
+        }
+    }
+
+    impl Flush for Panicky {
+        fn flush(&self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl InputScope for Panicky {
+        fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+            assert_ne!(name.join("."), "boom");
+            self.0.new_metric(name, kind)
+        }
+    }
+
+    let panicky = Panicky::default();
+    let dispatch = Dispatch::new(Registry::default().with(DipstickLayer::new(panicky.clone())));
+    dispatcher::with_default(&dispatch, || {
+        let result = panic::catch_unwind(AssertUnwindSafe(|| debug!(metrics.counter = "boom")));
+        assert!(result.is_err());
+        drop(active());
+        debug!(metrics.counter = "fine");
+    });
+    assert_eq!(0, panicky.0.get("scope.active"));
+    assert_eq!(1, panicky.0.get("fine"));
+}
+
+#[test]
+fn paired_on_events() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let _span = active().entered();
+            debug!(metrics.level.inc = "inflight");
+            assert_eq!(1, sums.get("scope.inflight"));
+            // Settles the increment of the parent, even from a nested span.
+            info_span!("Nested", metrics.counter = "nested")
+                .in_scope(|| debug!(metrics.level.dec = "inflight"));
+            assert_eq!(0, sums.get("scope.inflight"));
+        });
+        assert_eq!(0, sums.get("scope.active"));
+        // Not mistaken for levels named inc and dec.
+        assert_eq!(0, sums.get("scope.inc"));
+        assert_eq!(0, sums.get("scope.dec"));
+    });
+}
+
+/// A layer reporting the leaked levels into the returned list.
+fn detecting(sums: &Sums) -> (Arc<Mutex<Vec<String>>>, Dispatch) {
+    let leaks = Arc::new(Mutex::new(Vec::new()));
+    let reported = Arc::clone(&leaks);
+    let bridge =
+        DipstickLayer::new(sums.clone()).level_leaks(Some(Arc::new(move |leak: &LevelLeak| {
+            reported.lock().unwrap().push(leak.to_string())
+        })));
+    (leaks, Dispatch::new(Registry::default().with(bridge)))
+}
+
+#[test]
+fn leaked_paired() {
+    let sums = Sums::default();
+    let (leaks, dispatch) = detecting(&sums);
+    dispatcher::with_default(&dispatch, || {
+        active().in_scope(|| {
+            debug!(metrics.level.inc = "inflight");
+            debug!(metrics.level.inc = "inflight");
+            debug!(metrics.level.inc = "inflight");
+            debug!(metrics.level.dec = "inflight");
+            // A typo, this one doesn't pair with anything.
+            debug!(metrics.level.dec = "infligth");
+        });
+    });
+    let expected =
+        ["Level scope.inflight incremented 2 more times than decremented in span Active"];
+    assert_eq!(expected.as_slice(), *leaks.lock().unwrap());
+    assert_eq!(2, sums.get("scope.inflight"));
+}
+
+#[test]
+fn paired_per_scope() {
+    let sums = Sums::default();
+    let (leaks, dispatch) = detecting(&sums);
+    dispatcher::with_default(&dispatch, || {
+        active().in_scope(|| {
+            debug!(metrics.level.inc = "inflight");
+            // A level of the same name, but a different metric. It doesn't settle the increment.
+            info_span!("Other", metrics.scope = "other")
+                .in_scope(|| debug!(metrics.level.dec = "inflight"));
+        });
+    });
+    let expected =
+        ["Level scope.inflight incremented 1 more times than decremented in span Active"];
+    assert_eq!(expected.as_slice(), *leaks.lock().unwrap());
+    assert_eq!(1, sums.get("scope.inflight"));
+    assert_eq!(-1, sums.get("scope.other.inflight"));
+}
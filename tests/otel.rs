@@ -0,0 +1,144 @@
+//! Sending the metrics into OpenTelemetry instruments.
+#![cfg(feature = "opentelemetry")]
+
+use std::collections::HashMap;
+use std::thread;
+use std::time::Duration;
+
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::metrics::data::{AggregatedMetrics, MetricData};
+use opentelemetry_sdk::metrics::{InMemoryMetricExporter, PeriodicReader, SdkMeterProvider};
+use tracing::{debug, dispatcher, info_span, Dispatch};
+use tracing_dipstick::{DipstickLayer, OtelMeter};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+/// What got exported, simplified.
+#[derive(Debug, PartialEq)]
+enum Exported {
+    Counter(u64),
+    UpDown(i64),
+    Gauge(f64),
+    /// The count and sum of the recorded values.
+    Histogram(u64, f64),
+}
+
+/// Runs the instrumentation and collects the exported metrics by their names and attributes.
+fn collect(
+    sink: impl FnOnce(&SdkMeterProvider) -> OtelMeter,
+    f: impl FnOnce(),
+) -> HashMap<String, Exported> {
+    let exporter = InMemoryMetricExporter::default();
+    let provider = SdkMeterProvider::builder()
+        .with_reader(PeriodicReader::builder(exporter.clone()).build())
+        .build();
+    let dispatch = Dispatch::new(Registry::default().with(DipstickLayer::new(sink(&provider))));
+    dispatcher::with_default(&dispatch, f);
+    provider.force_flush().unwrap();
+
+    let mut result = HashMap::new();
+    let exported = exporter.get_finished_metrics().unwrap();
+    let metrics = exported
+        .iter()
+        .flat_map(|resource| resource.scope_metrics())
+        .flat_map(|scope| scope.metrics());
+    for metric in metrics {
+        let name = metric.name();
+        match metric.data() {
+            AggregatedMetrics::U64(MetricData::Sum(sum)) => {
+                for point in sum.data_points() {
+                    let key = key(name, point.attributes());
+                    result.insert(key, Exported::Counter(point.value()));
+                }
+            }
+            AggregatedMetrics::I64(MetricData::Sum(sum)) => {
+                for point in sum.data_points() {
+                    let key = key(name, point.attributes());
+                    result.insert(key, Exported::UpDown(point.value()));
+                }
+            }
+            AggregatedMetrics::F64(MetricData::Gauge(gauge)) => {
+                for point in gauge.data_points() {
+                    let key = key(name, point.attributes());
+                    result.insert(key, Exported::Gauge(point.value()));
+                }
+            }
+            AggregatedMetrics::F64(MetricData::Histogram(histogram)) => {
+                assert_eq!("s", metric.unit());
+                for point in histogram.data_points() {
+                    let key = key(name, point.attributes());
+                    let value = Exported::Histogram(point.count(), point.sum());
+                    result.insert(key, value);
+                }
+            }
+            other => panic!("Unexpected metric {:?}", other),
+        }
+    }
+    result
+}
+
+fn key<'a>(name: &str, attributes: impl Iterator<Item = &'a KeyValue>) -> String {
+    let attributes = attributes
+        .map(|kv| format!("{{{}={}}}", kv.key, kv.value))
+        .collect::<String>();
+    format!("{}{}", name, attributes)
+}
+
+fn yak() {
+    let _shaving = info_span!("Shaving", metrics.scope = "shaving").entered();
+    let _yak = info_span!(
+        "Yak",
+        metrics.scope = "yak",
+        metrics.timer = "time",
+        metrics.level = "active",
+        metrics.gauge.legs = 4,
+    )
+    .entered();
+    debug!(metrics.counter.hair = 3);
+    debug!(metrics.marker = "done");
+    thread::sleep(Duration::from_millis(10));
+}
+
+#[test]
+fn named() {
+    let metrics = collect(|provider| OtelMeter::new(provider.meter("test")), yak);
+    assert_eq!(metrics["shaving.yak.hair"], Exported::Counter(3));
+    assert_eq!(metrics["shaving.yak.done"], Exported::Counter(1));
+    assert_eq!(metrics["shaving.yak.legs"], Exported::Gauge(4.0));
+    assert_eq!(metrics["shaving.yak.active"], Exported::UpDown(0));
+    match metrics["shaving.yak.time"] {
+        Exported::Histogram(1, sum) => assert!((0.01..10.0).contains(&sum)),
+        ref other => panic!("Unexpected timer {:?}", other),
+    }
+}
+
+#[test]
+fn attributes() {
+    let sink = |provider: &SdkMeterProvider| {
+        OtelMeter::new(provider.meter("test")).scope_attribute("scope")
+    };
+    let metrics = collect(sink, || {
+        yak();
+        debug!(metrics.counter = "outside");
+        let _full = info_span!("Full", metrics.scope.full = "other").entered();
+        debug!(metrics.counter = "inside");
+    });
+    assert_eq!(metrics["hair{scope=shaving.yak}"], Exported::Counter(3));
+    assert_eq!(metrics["outside"], Exported::Counter(1));
+    assert_eq!(metrics["inside{scope=other}"], Exported::Counter(1));
+}
+
+#[test]
+fn floats() {
+    let metrics = collect(
+        |provider| OtelMeter::new(provider.meter("test")),
+        || {
+            debug!(metrics.gauge.load = 0.73);
+            debug!(metrics.counter.hair = 2.6);
+        },
+    );
+    assert_eq!(metrics["load"], Exported::Gauge(0.73));
+    // Counters hold integers.
+    assert_eq!(metrics["hair"], Exported::Counter(3));
+}
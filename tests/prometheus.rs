@@ -0,0 +1,170 @@
+//! Serving the metrics to Prometheus.
+#![cfg(feature = "prometheus")]
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use tracing::{debug, dispatcher, info_span, Dispatch};
+use tracing_dipstick::{DipstickLayer, PrometheusRegistry};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+fn dispatch(registry: &PrometheusRegistry) -> Dispatch {
+    let bridge = DipstickLayer::new(registry.clone()).level_max();
+    Dispatch::new(Registry::default().with(bridge))
+}
+
+fn get(addr: &str, path: &str) -> String {
+    let mut conn = TcpStream::connect(addr).unwrap();
+    write!(conn, "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n", path, addr).unwrap();
+    let mut response = String::new();
+    conn.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn rendered() {
+    let registry = PrometheusRegistry::new();
+    dispatcher::with_default(&dispatch(&registry), || {
+        let _shaving = info_span!("Shaving", metrics.scope = "shaving").entered();
+        let _yak = info_span!(
+            "Yak",
+            metrics.scope = "yak-1",
+            metrics.timer = "time",
+            metrics.level = "active",
+            metrics.gauge.legs = 4,
+        );
+        debug!(metrics.counter.hair = 3);
+        debug!(metrics.counter.hair = -1);
+        debug!(metrics.marker = "done");
+    });
+
+    let rendered = registry.render();
+    let expected = [
+        "# HELP shaving_done_total Marker shaving.done\n# TYPE shaving_done_total counter\n\
+         shaving_done_total 1\n",
+        "# HELP shaving_hair_total Counter shaving.hair\n# TYPE shaving_hair_total counter\n\
+         shaving_hair_total 3\n",
+        "# HELP shaving_yak_1_active Level shaving.yak-1.active\n\
+         # TYPE shaving_yak_1_active gauge\nshaving_yak_1_active 0\n",
+        "# HELP shaving_yak_1_active_max Gauge shaving.yak-1.active.max\n\
+         # TYPE shaving_yak_1_active_max gauge\nshaving_yak_1_active_max 1\n",
+        "# HELP shaving_yak_1_legs Gauge shaving.yak-1.legs\n\
+         # TYPE shaving_yak_1_legs gauge\nshaving_yak_1_legs 4\n",
+        "# HELP shaving_yak_1_time_seconds Timer shaving.yak-1.time\n\
+         # TYPE shaving_yak_1_time_seconds summary\n",
+        "\nshaving_yak_1_time_seconds_count 1\n",
+    ];
+    for expected in expected {
+        assert!(
+            rendered.contains(expected),
+            "{} not in:\n{}",
+            expected,
+            rendered
+        );
+    }
+    // A scrape is a flush for the extremes.
+    assert!(registry.render().contains("\nshaving_yak_1_active_max 0\n"));
+}
+
+#[test]
+fn served() {
+    let registry = PrometheusRegistry::new();
+    let addr = registry.serve("127.0.0.1:0").unwrap().to_string();
+    dispatcher::with_default(&dispatch(&registry), || {
+        debug!(metrics.counter = "requests")
+    });
+
+    let response = get(&addr, "/metrics");
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+    assert!(response.contains("\nrequests_total 1\n"), "{}", response);
+
+    assert!(get(&addr, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+}
+
+#[test]
+fn labeled() {
+    let registry = PrometheusRegistry::new();
+    dispatcher::with_default(&dispatch(&registry), || {
+        let _shaving = info_span!(
+            "Shaving",
+            metrics.scope = "shaving",
+            metrics.label.barber = "Bob \"the\" Shaver",
+        )
+        .entered();
+        let yak = info_span!(
+            "Yak",
+            metrics.level = "active",
+            metrics.label.yak = 1,
+            metrics.label.barber = "Alice",
+        );
+        yak.in_scope(|| debug!(metrics.counter.hair = 3));
+        debug!(metrics.counter.hair = 2);
+        drop(yak);
+    });
+
+    let rendered = registry.render();
+    let expected = [
+        "\nshaving_hair_total{barber=\"Alice\",yak=\"1\"} 3\n",
+        "\nshaving_hair_total{barber=\"Bob \\\"the\\\" Shaver\"} 2\n",
+        "\nshaving_active{barber=\"Alice\",yak=\"1\"} 0\n",
+        // The extremes are across all the labels.
+        "\nshaving_active_max 1\n",
+    ];
+    for expected in expected {
+        assert!(
+            rendered.contains(expected),
+            "{} not in:\n{}",
+            expected,
+            rendered
+        );
+    }
+    // Written only with the labels, there's no series without them.
+    assert!(!rendered.contains("\nshaving_active "), "{}", rendered);
+}
+
+#[test]
+fn floats() {
+    let registry = PrometheusRegistry::new();
+    dispatcher::with_default(&dispatch(&registry), || {
+        debug!(metrics.gauge.load = 0.73);
+        debug!(metrics.counter.hair = 2.5);
+        debug!(metrics.counter.hair = 0.25);
+        debug!(metrics.gauge.limit = f64::INFINITY);
+    });
+
+    let rendered = registry.render();
+    for expected in ["\nload 0.73\n", "\nhair_total 2.75\n", "\nlimit +Inf\n"] {
+        assert!(
+            rendered.contains(expected),
+            "{} not in:\n{}",
+            expected,
+            rendered
+        );
+    }
+}
+
+#[test]
+fn unwritten() {
+    let registry = PrometheusRegistry::new();
+    dispatcher::with_default(&dispatch(&registry), || {
+        // The timer is created with the span, but written only when it closes.
+        let _yak = info_span!("Yak", metrics.timer = "time").entered();
+        assert!(!registry.render().contains("time"), "{}", registry.render());
+    });
+    assert!(registry.render().contains("\ntime_seconds_count 1\n"));
+}
+
+#[test]
+fn observed_collision() {
+    let registry = PrometheusRegistry::new();
+    dispatcher::with_default(&dispatch(&registry), || {
+        debug!(metrics.gauge.active_max = 8);
+        // Its maximum would be published under the same name, but the gauge was there first.
+        let _span = info_span!("Active", metrics.level = "active").entered();
+    });
+    let rendered = registry.render();
+    assert!(rendered.contains("\nactive_max 8\n"), "{}", rendered);
+    assert!(!rendered.contains("\nactive_max 1\n"), "{}", rendered);
+}
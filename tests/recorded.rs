@@ -0,0 +1,79 @@
+//! The attributes declared as empty and filled in later through `Span::record`.
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use tracing::{debug, dispatcher, field, info_span};
+
+use common::both;
+
+#[test]
+fn counter_and_gauge() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = info_span!(
+                "Yak",
+                metrics.scope = "yak",
+                metrics.counter.hair = field::Empty,
+                metrics.gauge.legs = field::Empty,
+            );
+            assert_eq!(0, sums.get("yak.hair"));
+            // Sent at the time of recording, every time.
+            span.record("metrics.counter.hair", 3);
+            span.record("metrics.gauge.legs", 4);
+            assert_eq!(3, sums.get("yak.hair"));
+            assert_eq!(4, sums.get("yak.legs"));
+            span.record("metrics.counter.hair", 2);
+            span.record("metrics.gauge.legs", 3);
+        });
+        assert_eq!(5, sums.get("yak.hair"));
+        assert_eq!(3, sums.get("yak.legs"));
+    });
+}
+
+#[test]
+fn timer_restarted() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let whole = info_span!("Whole", metrics.timer = "whole");
+            let span = info_span!("Yak", metrics.timer = field::Empty);
+            thread::sleep(Duration::from_millis(100));
+            // Measures from here on, not since the creation of the span.
+            span.record("metrics.timer", "time");
+            thread::sleep(Duration::from_millis(100));
+            // Recorded again, starts over.
+            span.record("metrics.timer", "time");
+            thread::sleep(Duration::from_millis(10));
+            drop(span);
+            drop(whole);
+        });
+        let (time, whole) = (sums.get("time"), sums.get("whole"));
+        assert!(time >= 10_000, "{}", time);
+        // Both of the sleeps before the last record are left out.
+        assert!(whole - time >= 200_000, "{} - {}", whole, time);
+    });
+}
+
+#[test]
+fn scope_for_children() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = info_span!("Yak", metrics.scope = field::Empty);
+            span.in_scope(|| debug!(metrics.counter = "before"));
+            span.record("metrics.scope", "yak");
+            span.in_scope(|| {
+                debug!(metrics.counter = "after");
+                info_span!("Child", metrics.counter = "child").in_scope(|| {
+                    debug!(metrics.counter = "nested");
+                });
+            });
+        });
+        assert_eq!(1, sums.get("before"));
+        assert_eq!(0, sums.get("yak.before"));
+        assert_eq!(1, sums.get("yak.after"));
+        assert_eq!(1, sums.get("yak.child"));
+        assert_eq!(1, sums.get("yak.nested"));
+    });
+}
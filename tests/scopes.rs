@@ -0,0 +1,50 @@
+//! Nesting of the metrics in scopes.
+
+mod common;
+
+use tracing::{debug, dispatcher, info_span};
+
+use common::both;
+
+#[test]
+fn created_where_used() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let _shaving = info_span!("Shaving", metrics.scope = "shaving").entered();
+            debug!(metrics.counter.hair = 3);
+        });
+        assert_eq!(3, sums.get("shaving.hair"));
+        // Not prepared in the root when the callsite registers.
+        assert!(!sums.contains("hair"));
+    });
+}
+
+#[test]
+fn explicit_parents() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let yak = info_span!("Yak", metrics.scope = "yak");
+            let _shaving = info_span!("Shaving", metrics.scope = "shaving").entered();
+            // Nested in the explicit parent, not the current span.
+            info_span!(parent: &yak, "Hair", metrics.counter = "span").in_scope(|| {
+                debug!(metrics.counter = "inside");
+            });
+            debug!(parent: &yak, metrics.counter = "event");
+            // Root ones, outside of any scope.
+            info_span!(parent: None, "Hair", metrics.counter = "span").in_scope(|| {
+                debug!(metrics.counter = "inside");
+            });
+            debug!(parent: None, metrics.counter = "event");
+            // And the contextual one still in the current span.
+            debug!(metrics.counter = "event");
+        });
+        for name in ["yak.span", "yak.inside", "yak.event"] {
+            assert_eq!(1, sums.get(name), "{}", name);
+        }
+        for name in ["span", "inside", "event", "shaving.event"] {
+            assert_eq!(1, sums.get(name), "{}", name);
+        }
+        assert_eq!(0, sums.get("shaving.span"));
+        assert_eq!(0, sums.get("shaving.inside"));
+    });
+}
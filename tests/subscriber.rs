@@ -0,0 +1,121 @@
+//! The [`DipstickSubscriber`] forwarding to an inner subscriber that is interested in everything.
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::{dispatcher, info, info_span, Dispatch, Event, Subscriber};
+use tracing_dipstick::{DipstickLayer, DipstickSubscriber};
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::{Layer, Registry};
+
+use common::Sums;
+
+/// Counts the fields of an event.
+struct FieldCount(usize);
+
+impl Visit for FieldCount {
+    fn record_debug(&mut self, _: &Field, _: &dyn std::fmt::Debug) {
+        self.0 += 1;
+    }
+}
+
+/// Logs what the inner subscriber sees, with the spans identified by their names.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+impl Recorder {
+    fn log(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+impl<S> Layer<S> for Recorder
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &Attributes, id: &Id, ctx: Context<S>) {
+        let span = ctx.span(id).unwrap();
+        let parent = span.parent().map_or("root", |parent| parent.name());
+        let entry = format!("new {} in {}", span.name(), parent);
+        self.0.lock().unwrap().push(entry);
+    }
+
+    fn on_event(&self, event: &Event, ctx: Context<S>) {
+        let mut fields = FieldCount(0);
+        event.record(&mut fields);
+        let parent = ctx.event_span(event);
+        let parent = parent.as_ref().map_or("root", |parent| parent.name());
+        let entry = format!("event with {} fields in {}", fields.0, parent);
+        self.0.lock().unwrap().push(entry);
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<S>) {
+        let entry = format!("close {}", ctx.span(&id).unwrap().name());
+        self.0.lock().unwrap().push(entry);
+    }
+}
+
+fn recorded() -> (Sums, Recorder, Dispatch) {
+    let sums = Sums::default();
+    let recorder = Recorder::default();
+    let inner = Registry::default().with(recorder.clone());
+    let subscriber = DipstickSubscriber::new(DipstickLayer::new(sums.clone()), inner);
+    (sums, recorder, Dispatch::new(subscriber))
+}
+
+#[test]
+fn forwarded() {
+    let (sums, recorder, dispatch) = recorded();
+    dispatcher::with_default(&dispatch, || {
+        let parent = info_span!("parent", metrics.scope = "shaving");
+        let child = parent.in_scope(|| info_span!("child", metrics.counter = "yaks"));
+        let other = info_span!(parent: None, "other");
+        let explicit = info_span!(parent: &other, "explicit", metrics.scope = "other");
+        parent.in_scope(|| {
+            info!(parent: &explicit, metrics.counter.hair = 1);
+            info!(parent: None, "Rootless");
+            info!("Contextual");
+        });
+        // The child keeps the parent open, the inner subscriber closes both through the dispatcher.
+        drop(parent);
+        drop(child);
+        drop(explicit);
+        drop(other);
+    });
+    let expected = [
+        "new parent in root",
+        "new child in parent",
+        "new other in root",
+        "new explicit in other",
+        "event with 1 fields in explicit",
+        "event with 1 fields in root",
+        "event with 1 fields in parent",
+        "close child",
+        "close parent",
+        "close explicit",
+        "close other",
+    ];
+    assert_eq!(expected.as_slice(), recorder.log());
+    assert_eq!(1, sums.get("shaving.yaks"));
+    assert_eq!(1, sums.get("other.hair"));
+}
+
+#[test]
+fn many_fields() {
+    let (sums, recorder, dispatch) = recorded();
+    dispatcher::with_default(&dispatch, || {
+        let parent = info_span!("parent");
+        info!(parent: &parent, metrics.counter.hair = 1, f0 = 0, f1 = 1, f2 = 2, f3 = 3, f4 = 4, f5 = 5, f6 = 6, f7 = 7, f8 = 8, f9 = 9, f10 = 10, f11 = 11, f12 = 12, f13 = 13, f14 = 14, f15 = 15, f16 = 16, f17 = 17, f18 = 18, f19 = 19, f20 = 20, f21 = 21, f22 = 22, f23 = 23, f24 = 24, f25 = 25, f26 = 26, f27 = 27, f28 = 28, f29 = 29, f30 = 30, f31 = 31, f32 = 32, f33 = 33, f34 = 34, f35 = 35, f36 = 36, f37 = 37, f38 = 38, f39 = 39);
+    });
+    let expected = [
+        "new parent in root",
+        "event with 41 fields in parent",
+        "close parent",
+    ];
+    assert_eq!(expected.as_slice(), recorder.log());
+    assert_eq!(1, sums.get("hair"));
+}
@@ -0,0 +1,168 @@
+//! The timers splitting the time of spans in different ways.
+
+mod common;
+
+use std::thread;
+use std::time::Duration;
+
+use tracing::{dispatcher, field, info_span};
+
+use common::both;
+
+/// Spins on the current thread until it consumed at least this much CPU time.
+///
+/// Returns the CPU time actually consumed.
+#[cfg(any(unix, windows))]
+fn spin(time: Duration) -> Duration {
+    let start = cpu_time::ThreadTime::now();
+    while start.elapsed() < time {}
+    start.elapsed()
+}
+
+#[test]
+#[cfg(any(unix, windows))]
+fn cpu_on_threads() {
+    both(|sums, dispatch| {
+        let consumed = dispatcher::with_default(&dispatch, || {
+            let span = info_span!("Compute", metrics.cpu_timer = "cpu");
+            let consumed = thread::scope(|s| {
+                let threads: Vec<_> = (0..2)
+                    .map(|_| {
+                        let span = span.clone();
+                        let dispatch = dispatch.clone();
+                        s.spawn(move || {
+                            dispatcher::with_default(&dispatch, || {
+                                span.in_scope(|| spin(Duration::from_millis(50)))
+                            })
+                        })
+                    })
+                    .collect();
+                threads
+                    .into_iter()
+                    .map(|thread| thread.join().unwrap())
+                    .sum::<Duration>()
+            });
+            // Sleeping is not computing.
+            span.in_scope(|| thread::sleep(Duration::from_millis(200)));
+            consumed
+        });
+        // Both threads are counted, even though they were inside at the same time.
+        let micros = sums.get("cpu") as u128;
+        assert!(
+            micros >= consumed.as_micros(),
+            "{} < {:?}",
+            micros,
+            consumed
+        );
+        assert!(
+            micros < consumed.as_micros() + 100_000,
+            "{} vs {:?}",
+            micros,
+            consumed
+        );
+    });
+}
+
+#[test]
+fn busy_and_idle() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = info_span!(
+                "Polled",
+                metrics.timer.busy = "busy",
+                metrics.timer.idle = "idle",
+            );
+            thread::sleep(Duration::from_millis(200));
+            span.in_scope(|| thread::sleep(Duration::from_millis(50)));
+        });
+        let (busy, idle) = (sums.get("busy"), sums.get("idle"));
+        assert!(busy >= 50_000, "{}", busy);
+        assert!(idle >= 200_000, "{}", idle);
+        assert!(idle > busy, "{} <= {}", idle, busy);
+    });
+}
+
+#[test]
+fn queued() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let span = info_span!("Job", metrics.timer.queue = "queue", metrics.timer = "life");
+            thread::sleep(Duration::from_millis(50));
+            assert_eq!(0, sums.get("queue"));
+            // Sent on the first enter already.
+            span.in_scope(|| thread::sleep(Duration::from_millis(50)));
+            let queued = sums.get("queue");
+            assert!(queued >= 50_000, "{}", queued);
+            // Not again on the later ones, nor when closing.
+            span.in_scope(|| ());
+            drop(span);
+            assert_eq!(queued, sums.get("queue"));
+            // The time inside is not part of it.
+            assert!(sums.get("life") - queued >= 50_000);
+
+            // Never taken out of the queue.
+            let span = info_span!("Job", metrics.timer.queue = "dropped");
+            thread::sleep(Duration::from_millis(10));
+            drop(span);
+        });
+        assert!(sums.contains("dropped"));
+        assert_eq!(0, sums.get("dropped"));
+    });
+}
+
+#[test]
+fn queued_late() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            // Recorded before the enter, sent when entering.
+            let span = info_span!("Job", metrics.timer.queue = field::Empty);
+            span.record("metrics.timer.queue", "before");
+            thread::sleep(Duration::from_millis(50));
+            span.in_scope(|| ());
+            let queued = sums.get("before");
+            assert!(queued >= 50_000, "{}", queued);
+
+            // Recorded after the enter, sent right away.
+            let span = info_span!(
+                "Job",
+                metrics.timer.queue = field::Empty,
+                metrics.timer = "life"
+            );
+            thread::sleep(Duration::from_millis(50));
+            span.in_scope(|| thread::sleep(Duration::from_millis(50)));
+            span.record("metrics.timer.queue", "after");
+            let queued = sums.get("after");
+            assert!(queued >= 50_000, "{}", queued);
+            // Still only until the first enter.
+            drop(span);
+            assert!(sums.get("life") - queued >= 50_000);
+        });
+    });
+}
+
+#[test]
+fn self_time() {
+    both(|sums, dispatch| {
+        dispatcher::with_default(&dispatch, || {
+            let parent = info_span!(
+                "Parent",
+                metrics.timer.self = "own",
+                metrics.timer = "total"
+            );
+            parent.in_scope(|| {
+                info_span!("Timed", metrics.timer = "timed")
+                    .in_scope(|| thread::sleep(Duration::from_millis(100)));
+                // Not subtracted, there's no timer to tell how long it took.
+                info_span!("Untimed", metrics.counter = "untimed")
+                    .in_scope(|| thread::sleep(Duration::from_millis(50)));
+                thread::sleep(Duration::from_millis(20));
+            });
+        });
+        let (own, timed, total) = (sums.get("own"), sums.get("timed"), sums.get("total"));
+        assert!(timed >= 100_000, "{}", timed);
+        // The untimed child stays in.
+        assert!(own >= 70_000, "{}", own);
+        // While the timed one is taken out.
+        assert!(total - own >= 100_000, "{} - {}", total, own);
+    });
+}